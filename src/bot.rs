@@ -0,0 +1,304 @@
+//! Headless bots, which play the game without a window, for load and soak testing.
+
+use std::{
+    error::Error,
+    fmt::Display,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client,
+    config::{ClientConfig, Config},
+    math::Rng,
+    model::Game,
+    netcode, networking, sys,
+};
+
+/// How long the bots play, unless configured.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+
+/// Frames between the turns of walking bots.
+const TURN_FRAMES: u64 = 60;
+
+/// Frames between the direction changes of random bots.
+const RANDOM_FRAMES: u64 = 20;
+
+/// How a bot moves.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    /// Stand still.
+    Idle,
+    /// Walk back and forth, jumping at each turn.
+    Walk,
+    /// Jump in place whenever possible.
+    Jump,
+    /// Move and jump at random.
+    Random,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(Pattern::Idle),
+            "walk" => Ok(Pattern::Walk),
+            "jump" => Ok(Pattern::Jump),
+            "random" => Ok(Pattern::Random),
+            _ => Err(format!("unknown bot pattern: {s}")),
+        }
+    }
+}
+
+/// Generates the movement of one bot, one frame at a time.
+struct Script {
+    pattern: Pattern,
+    rng: Rng,
+    frame: u64,
+    movement: (i8, i8),
+}
+
+impl Script {
+    fn new(pattern: Pattern, rng: Rng) -> Self {
+        Self {
+            pattern,
+            rng,
+            frame: 0,
+            movement: (0, 0),
+        }
+    }
+
+    /// The movement of the next frame.
+    fn next(&mut self) -> (i8, i8) {
+        let frame = self.frame;
+        self.frame += 1;
+
+        match self.pattern {
+            Pattern::Idle => (0, 0),
+            Pattern::Walk => {
+                let x = if (frame / TURN_FRAMES).is_multiple_of(2) {
+                    1
+                } else {
+                    -1
+                };
+                let y = if frame.is_multiple_of(TURN_FRAMES) {
+                    -1
+                } else {
+                    0
+                };
+                (x, y)
+            }
+            Pattern::Jump => (0, -1),
+            Pattern::Random => {
+                if frame.is_multiple_of(RANDOM_FRAMES) {
+                    let x = (self.rng.next_u64() % 3) as i8 - 1;
+                    let y = if self.rng.chance(0.3) { -1 } else { 0 };
+                    self.movement = (x, y);
+                }
+                self.movement
+            }
+        }
+    }
+}
+
+/// Measurements of one bot.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Time from the first connection request until the server accepted the bot.
+    pub accepted_after: Duration,
+    /// The number of inputs sent to the server.
+    pub inputs: usize,
+    /// The number of snapshots received from the server and applied.
+    pub snapshots: usize,
+    /// The number of snapshots which arrived after a newer one.
+    pub stale: usize,
+    /// The number of snapshots which arrived more than once.
+    pub duplicate: usize,
+    /// The most inputs which were waiting for acknowledgment at the same time.
+    pub max_unacknowledged: usize,
+    /// The round-trip time measured by clock synchronization at the end.
+    pub rtt: Option<Duration>,
+    /// The number of times the server's buffer of inputs from the bot ran dry.
+    pub input_underruns: usize,
+    /// The number of times the game was rolled back, in rollback mode.
+    pub rollbacks: usize,
+    /// The number of frames the bot had to wait for, in lockstep mode.
+    pub stalls: usize,
+    /// The number of checksums which differed from the server's, in lockstep mode.
+    pub desyncs: usize,
+    /// How the bot's predictions compared with the server's states.
+    pub mispredictions: netcode::Mispredictions,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "accepted after {:?}, {} inputs, {} snapshots ({} stale, {} duplicate), up to {} unacknowledged inputs, measured RTT {:?}, {} input buffer underruns, {} rollbacks, {} stalls, {} desyncs, {} of {} predictions wrong (max error {:.2})",
+            self.accepted_after,
+            self.inputs,
+            self.snapshots,
+            self.stale,
+            self.duplicate,
+            self.max_unacknowledged,
+            self.rtt.unwrap_or_default(),
+            self.input_underruns,
+            self.rollbacks,
+            self.stalls,
+            self.desyncs,
+            self.mispredictions.count,
+            self.mispredictions.checked,
+            self.mispredictions.max_error
+        )
+    }
+}
+
+/// Run the configured number of bots against the server, and print their measurements.
+///
+/// Each bot plays on its own thread, with the configured client settings.
+/// Fails if any bot was not accepted or did not receive any snapshots.
+pub fn run(config: &Config, shared: Game) -> Result<(), Box<dyn Error>> {
+    let count = config.bot.count;
+    let mut rng = Rng::from_time();
+    let bots: Vec<_> = (0..count)
+        .map(|_| {
+            let codec_name = config.codec.clone();
+            let server = (config.host.clone(), config.port);
+            let client = config.client.clone();
+            let shared = shared.clone();
+            let script = Script::new(config.bot.pattern, Rng::new(rng.next_u64()));
+            let duration = config.bot.duration();
+            thread::spawn(move || {
+                let codec = networking::codec(&codec_name).ok_or("unknown codec")?;
+                play(&*codec, server, &client, shared, script, duration).map_err(|e| e.to_string())
+            })
+        })
+        .collect();
+
+    let mut failed = 0;
+    for (i, bot) in bots.into_iter().enumerate() {
+        match bot.join().unwrap_or_else(|_| Err(String::from("panicked"))) {
+            Ok(stats) => println!("bot {i}: {stats}"),
+            Err(e) => {
+                println!("bot {i}: {e}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{failed} of {count} bots failed").into());
+    }
+    Ok(())
+}
+
+/// Play as one bot until `duration` has passed.
+fn play(
+    codec: &dyn networking::Codec,
+    server: (String, u16),
+    settings: &ClientConfig,
+    shared: Game,
+    mut script: Script,
+    duration: Duration,
+) -> Result<Stats, Box<dyn Error>> {
+    let conditions = networking::Conditions {
+        ping_ms: settings.ping_ms,
+        ..networking::Conditions::NONE
+    };
+    let mut state = client::State::new(shared);
+    let mut session = client::Session::connect(codec, server, conditions)?;
+    session.set_redundancy(settings.redundancy);
+    let mut netcode = netcode::init(
+        settings.interpolation_delay(),
+        settings.underrun,
+        settings.smoothing(),
+    );
+
+    let start = Instant::now();
+    let mut stats = Stats::default();
+
+    while start.elapsed() < duration {
+        let tick = sys::ticker(session.frame_time()).start();
+
+        let joined = state.player_idx.is_some();
+        let movement = if joined { script.next() } else { (0, 0) };
+
+        session.send(&state, &mut netcode, movement)?;
+        let received = session.receive(&mut state, &mut netcode)?;
+        netcode.apply(
+            &mut state,
+            received.move_ack_id,
+            movement,
+            settings.features(),
+        );
+
+        if joined {
+            stats.inputs += 1;
+        } else if state.player_idx.is_some() {
+            stats.accepted_after = start.elapsed();
+        }
+        stats.snapshots += received.snapshots;
+        stats.max_unacknowledged = stats.max_unacknowledged.max(netcode.unacknowledged());
+
+        tick.wait();
+    }
+
+    session.disconnect()?;
+
+    if state.player_idx.is_none() {
+        return Err("not accepted by the server".into());
+    }
+    let counters = session.counters();
+    stats.stale = counters.stale;
+    stats.duplicate = counters.duplicate;
+    stats.rtt = netcode.rtt();
+    stats.input_underruns = session.input_status().underruns;
+    stats.mispredictions = netcode.mispredictions();
+    stats.rollbacks = netcode.rollback_stats().map_or(0, |s| s.rollbacks);
+    if let Some(lockstep) = netcode.lockstep_stats() {
+        stats.stalls = lockstep.stalls;
+        stats.desyncs = lockstep.desyncs;
+    }
+
+    if stats.snapshots == 0 {
+        return Err("did not receive any snapshots".into());
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_parse() {
+        assert_eq!("walk".parse(), Ok(Pattern::Walk));
+        assert_eq!("random".parse(), Ok(Pattern::Random));
+        assert!("fly".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn walking_bots_turn_and_jump() {
+        let mut script = Script::new(Pattern::Walk, Rng::new(1));
+        assert_eq!(script.next(), (1, -1));
+        assert_eq!(script.next(), (1, 0));
+
+        let moves: Vec<_> = (2..=TURN_FRAMES).map(|_| script.next()).collect();
+        assert_eq!(moves.last(), Some(&(-1, -1)));
+    }
+
+    #[test]
+    fn random_bots_are_deterministic_and_in_range() {
+        let mut a = Script::new(Pattern::Random, Rng::new(3));
+        let mut b = Script::new(Pattern::Random, Rng::new(3));
+        for _ in 0..1000 {
+            let (x, y) = a.next();
+            assert_eq!((x, y), b.next());
+            assert!((-1..=1).contains(&x));
+            assert!((-1..=0).contains(&y));
+        }
+    }
+}
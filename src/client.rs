@@ -1,89 +1,411 @@
 //! The client game loop.
 
-use std::{error::Error, fmt::Display, time::Duration};
+use std::{
+    error::Error,
+    fmt::Display,
+    io,
+    time::{Duration, Instant},
+};
 
 use sdl2::{EventPump, keyboard::Keycode};
 
-use crate::{model::*, netcode, networking, render, server, sys};
+use crate::{
+    config::Config,
+    model::*,
+    net_graph::{Measurements, NetGraph},
+    netcode, networking, render, snapshot, sys,
+};
 
-const FRAME_TIME: Duration = Duration::from_nanos(16_666_666);
+/// Time between client frames.
+pub const FRAME_TIME: Duration = Duration::from_nanos(16_666_666);
+
+/// Minimum time between requests for the level, while the client is missing it.
+const LEVEL_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Minimum time between connection requests, while the server has not answered.
+const CONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time between pings for synchronizing the clock with the server.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Change in the frame rate for each input the server's input buffer is away from its target depth.
+const RATE_ADJUSTMENT_PER_INPUT: f64 = 0.02;
+
+/// Maximum change in the frame rate while keeping the server's input buffer at its target depth.
+const MAX_RATE_ADJUSTMENT: f64 = 0.05;
+
+/// Earlier unacknowledged movements sent along with each movement, unless configured.
+pub const DEFAULT_REDUNDANCY: usize = 8;
+
+/// The most earlier movements which may be sent along with each movement,
+/// which keeps messages well below the size limit of a datagram.
+pub const MAX_REDUNDANCY: usize = 64;
+
+/// Simulated round-trip time at start-up, unless configured.
+pub const DEFAULT_PING_MS: u64 = 250;
 
 /// Client-observed time delta.
 pub const DELTA_TIME: f64 = FRAME_TIME.as_secs_f64();
 
-/// Run the client.
+/// Run the client, connecting to the configured server.
 pub fn run(
     mut sdl: sys::SdlContext,
     font: &sdl2::ttf::Font,
+    codec: &dyn networking::Codec,
     shared: Game,
+    config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    let mut state = State {
-        player_idx: None,
-        shared,
-    };
+    let mut state = State::new(shared);
 
     let mut settings = Settings {
-        reconciliation: false,
-        interpolation: false,
-        prediction: false,
-        ping_ms: 250,
+        features: config.client.features(),
+        conditions: networking::Conditions {
+            ping_ms: config.client.ping_ms,
+            ..networking::Conditions::NONE
+        },
+        net_graph: config.client.net_graph,
     };
+    let mut net_graph = NetGraph::new();
 
-    let client = networking::Client::connect((server::HOST, server::PORT), settings.ping_ms)?;
-
-    let ticker = sys::ticker(FRAME_TIME);
+    let mut session = Session::connect(codec, config.addr(), settings.conditions)?;
+    session.set_redundancy(config.client.redundancy);
 
     let mut movement = (0, 0);
 
-    let mut netcode = netcode::init();
+    let mut netcode = netcode::init(
+        config.client.interpolation_delay(),
+        config.client.underrun,
+        config.client.smoothing(),
+    );
 
     let mut running = true;
     while running {
-        let tick = ticker.start();
+        let tick = sys::ticker(session.frame_time()).start();
 
         handle_client_inputs(&mut sdl.events, &mut settings, &mut movement, &mut running);
-        client.set_ping(settings.ping_ms);
-
-        // Handling of movement history for reconciliation
-        let id = netcode.push_movement(movement);
-
-        let message = Message {
-            id,
-            x: movement.0,
-            y: movement.1,
-        };
-
-        client.send(&message)?;
-
-        let mut move_ack_id = 0;
-        for bytes in client.recv() {
-            let server_response: ServerResponse = serde_json::from_slice(&bytes).unwrap();
-            state.player_idx = Some(server_response.player_idx);
-            state.shared = server_response.game;
-            move_ack_id = server_response.ack_id;
-            netcode.update(state.shared.players.clone());
+        if session.client().conditions() != settings.conditions {
+            session.client().set_conditions(settings.conditions);
         }
 
+        session.send(&state, &mut netcode, movement)?;
+        let received = session.receive(&mut state, &mut netcode)?;
+
         // apply the enabled netcode features
         netcode.apply(
             &mut state,
-            move_ack_id,
+            received.move_ack_id,
             movement,
-            settings.prediction,
-            settings.reconciliation,
-            settings.interpolation,
+            settings.features,
         );
 
-        render::game(&state.shared, &mut sdl.canvas);
-        render::settings(&mut sdl, font, settings.to_string().lines());
+        render::game(&netcode.displayed(&state), &mut sdl.canvas);
+        let counters = session.counters();
+        net_graph.push(
+            Instant::now(),
+            Measurements {
+                rtt: netcode.rtt(),
+                jitter: netcode.rtt_jitter(),
+                traffic: session.client().traffic(),
+                snapshots: counters.applied + counters.stale,
+                unacknowledged: netcode.unacknowledged(),
+                correction: netcode.correction(),
+                interpolation_depth: netcode.interpolation_depth(),
+            },
+        );
+        // the graph takes the place of the text, which is too wide to share the window with it
+        if settings.net_graph {
+            render::net_graph(&mut sdl, font, &net_graph);
+        } else {
+            let text = format!(
+                "{settings}\n{}\n{}\nInterpolation delay: {:?}\nUnderruns: {}\nSmoothing error: {:.1}\n{}\n{}\n{}",
+                ModeText(&netcode),
+                ClockText(&netcode),
+                netcode.interpolation_delay(),
+                netcode.underruns(),
+                netcode.smoothing_error().len(),
+                netcode.mispredictions(),
+                counters,
+                session.input_status()
+            );
+            render::settings(&mut sdl, font, text.lines());
+        }
         sdl.canvas.present();
 
         tick.wait();
     }
 
+    session.disconnect()?;
+
     Ok(())
 }
 
+/// The connection of a client to the server, independent of how the client is displayed or controlled.
+///
+/// Takes care of the handshake, the level and decoding snapshots.
+pub struct Session<'a> {
+    client: networking::Client,
+    codec: &'a dyn networking::Codec,
+    snapshots: snapshot::History,
+    snapshot_ack: usize,
+    /// The sequence number of the newest snapshot applied.
+    latest_sequence: usize,
+    counters: SnapshotCounters,
+    level: LevelSync,
+    connect: Cooldown,
+    clock_sync: Cooldown,
+    /// The number of earlier movements sent along with each movement.
+    redundancy: usize,
+    /// The server's buffer of inputs from this client, as of the newest snapshot.
+    input: InputStatus,
+}
+
+/// Counts of the snapshots a `Session` has received, for debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SnapshotCounters {
+    /// Snapshots which were newer than all earlier ones, and replaced the game state.
+    pub applied: usize,
+    /// Snapshots which were received more than once.
+    pub duplicate: usize,
+    /// Snapshots which arrived after a newer one. They are only used for interpolation.
+    pub stale: usize,
+    /// Delta compressed snapshots whose baseline the client no longer has.
+    pub undecodable: usize,
+}
+
+impl Display for SnapshotCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Snapshots: {} applied, {} duplicate, {} stale, {} undecodable",
+            self.applied, self.duplicate, self.stale, self.undecodable
+        )
+    }
+}
+
+/// What a `Session` received from the server during one frame.
+#[derive(Debug, Default)]
+pub struct Received {
+    /// The number of snapshots received.
+    pub snapshots: usize,
+    /// The id of the last movement acknowledged by the server, or 0 if no snapshot was received.
+    pub move_ack_id: usize,
+}
+
+impl<'a> Session<'a> {
+    /// Connect to the server, with the given simulated network conditions.
+    pub fn connect<A>(
+        codec: &'a dyn networking::Codec,
+        server: A,
+        conditions: networking::Conditions,
+    ) -> io::Result<Self>
+    where
+        A: std::net::ToSocketAddrs,
+    {
+        let client = networking::Client::connect(server, conditions.ping_ms)?;
+        client.set_conditions(conditions);
+
+        Ok(Self {
+            client,
+            codec,
+            snapshots: snapshot::History::default(),
+            snapshot_ack: 0,
+            latest_sequence: 0,
+            counters: SnapshotCounters::default(),
+            level: LevelSync::new(),
+            connect: Cooldown::new(CONNECT_INTERVAL),
+            clock_sync: Cooldown::new(CLOCK_SYNC_INTERVAL),
+            redundancy: DEFAULT_REDUNDANCY,
+            input: InputStatus::default(),
+        })
+    }
+
+    pub fn client(&self) -> &networking::Client {
+        &self.client
+    }
+
+    pub fn counters(&self) -> SnapshotCounters {
+        self.counters
+    }
+
+    /// The server's buffer of inputs from this client, as of the newest snapshot.
+    pub fn input_status(&self) -> InputStatus {
+        self.input
+    }
+
+    /// The time between frames, adjusted to keep the server's buffer of inputs from this client at its target depth.
+    ///
+    /// The client sends one input per frame, so it runs slightly faster while the buffer is too shallow,
+    /// and slightly slower while it is too deep.
+    pub fn frame_time(&self) -> Duration {
+        let error = self.input.depth as f64 - self.input.target as f64;
+        let adjustment =
+            (error * RATE_ADJUSTMENT_PER_INPUT).clamp(-MAX_RATE_ADJUSTMENT, MAX_RATE_ADJUSTMENT);
+        FRAME_TIME.mul_f64(1. + adjustment)
+    }
+
+    /// Set the number of earlier unacknowledged movements sent along with each movement, or 0 for none.
+    pub fn set_redundancy(&mut self, redundancy: usize) {
+        self.redundancy = redundancy;
+    }
+
+    /// Send the movement of this frame, or a connection request while the server has not accepted the client.
+    ///
+    /// In `Mode::Rollback`, the newest inputs already simulated are sent instead, to be relayed to the other clients,
+    /// and in `Mode::Lockstep` the inputs queued for the coming frames.
+    /// Once accepted, the client also pings the server regularly to synchronize the clocks.
+    pub fn send(
+        &mut self,
+        state: &State,
+        netcode: &mut netcode::Netcode,
+        movement: (i8, i8),
+    ) -> io::Result<()> {
+        if let Some(player_idx) = state.player_idx {
+            let packet = match netcode.mode() {
+                Mode::Authoritative => {
+                    // Handling of movement history for reconciliation
+                    let id = netcode.push_movement(movement);
+
+                    Some(ClientPacket::Input(Message {
+                        id,
+                        x: movement.0,
+                        y: movement.1,
+                        snapshot_ack: self.snapshot_ack,
+                        redundant: netcode.redundant(self.redundancy),
+                    }))
+                }
+                Mode::Rollback => netcode
+                    .local_inputs(player_idx, self.redundancy + 1)
+                    .map(|(frame, inputs)| ClientPacket::PeerInput { frame, inputs }),
+                Mode::Lockstep => netcode.lockstep_input(player_idx),
+            };
+            if let Some(packet) = packet {
+                self.client.send(&self.codec.encode_client(&packet))?;
+            }
+
+            if self.clock_sync.ready() {
+                let ping = ClientPacket::Ping {
+                    client_time_us: netcode.ping_time(),
+                };
+                self.client.send(&self.codec.encode_client(&ping))?;
+            }
+        } else if self.connect.ready() {
+            let packet = ClientPacket::Connect {
+                protocol_version: PROTOCOL_VERSION,
+            };
+            self.client.send(&self.codec.encode_client(&packet))?;
+        }
+        Ok(())
+    }
+
+    /// Handle all pending packets from the server, updating the state and the netcode.
+    ///
+    /// Fails if the server rejects the client.
+    pub fn receive(
+        &mut self,
+        state: &mut State,
+        netcode: &mut netcode::Netcode,
+    ) -> Result<Received, Box<dyn Error>> {
+        let mut received = Received::default();
+        for bytes in self.client.recv() {
+            let server_response = match self.codec.decode_server(&bytes) {
+                Some(ServerPacket::Snapshot(server_response)) => server_response,
+                Some(ServerPacket::Welcome {
+                    platforms,
+                    level_hash,
+                    physics,
+                }) => {
+                    state.shared.platforms = platforms;
+                    state.shared.physics = physics;
+                    self.level.hash = Some(level_hash);
+                    continue;
+                }
+                Some(ServerPacket::Accept {
+                    player_idx, mode, ..
+                }) => {
+                    state.player_idx.get_or_insert(player_idx);
+                    netcode.set_mode(mode);
+                    continue;
+                }
+                Some(ServerPacket::PeerInput {
+                    player_idx,
+                    frame,
+                    inputs,
+                }) => {
+                    netcode.peer_input(player_idx, frame, &inputs);
+                    continue;
+                }
+                Some(ServerPacket::LockstepStart { frame, players }) => {
+                    netcode.start_lockstep(frame, players);
+                    continue;
+                }
+                Some(ServerPacket::LockstepInputs {
+                    frame,
+                    inputs,
+                    checksum,
+                }) => {
+                    netcode.lockstep_frames(frame, inputs, checksum);
+                    continue;
+                }
+                Some(ServerPacket::Pong {
+                    client_time_us,
+                    server_time_us,
+                }) => {
+                    netcode.pong(client_time_us, server_time_us);
+                    continue;
+                }
+                Some(ServerPacket::Reject(reason)) => return Err(reason.into()),
+                None => continue,
+            };
+            let sequence = server_response.sequence;
+            if sequence == self.latest_sequence || self.snapshots.get(sequence).is_some() {
+                self.counters.duplicate += 1;
+                continue;
+            }
+
+            let Some(players) = self.snapshots.decode(sequence, server_response.snapshot) else {
+                self.counters.undecodable += 1;
+                continue;
+            };
+            let time = Duration::from_micros(server_response.time_us);
+            netcode.update(server_response.tick, time, players.clone());
+
+            if sequence < self.latest_sequence {
+                // arrived out of order, so it is only useful for interpolation
+                self.counters.stale += 1;
+                continue;
+            }
+            self.counters.applied += 1;
+            self.latest_sequence = sequence;
+            self.level.server_hash = Some(server_response.level_hash);
+            self.snapshot_ack = sequence;
+            self.input = server_response.input;
+            if let (Some(hash), Some(Some(player))) = (
+                server_response.player_hash,
+                state.player_idx.and_then(|i| players.get(i)),
+            ) {
+                netcode.check_prediction(server_response.ack_id, hash, player);
+            }
+
+            state.shared.players = players;
+            received.snapshots += 1;
+            received.move_ack_id = received.move_ack_id.max(server_response.ack_id);
+        }
+
+        if state.player_idx.is_some() && self.level.should_request() {
+            self.client
+                .send(&self.codec.encode_client(&ClientPacket::LevelRequest))?;
+        }
+
+        Ok(received)
+    }
+
+    /// Tell the server that the client is leaving.
+    pub fn disconnect(&self) -> io::Result<()> {
+        self.client
+            .send_immediately(&self.codec.encode_client(&ClientPacket::Disconnect))
+    }
+}
+
 fn handle_client_inputs(
     events: &mut EventPump,
     settings: &mut Settings,
@@ -104,12 +426,17 @@ fn handle_client_inputs(
                 Keycode::S => movement.1 += 1,
                 Keycode::A => movement.0 -= 1,
                 Keycode::D => movement.0 += 1,
-                Keycode::I => settings.interpolation = !settings.interpolation,
-                Keycode::P => settings.prediction = !settings.prediction,
-                Keycode::R => settings.reconciliation = !settings.reconciliation,
+                Keycode::I => settings.features.interpolation ^= true,
+                Keycode::P => settings.features.prediction ^= true,
+                Keycode::R => settings.features.reconciliation ^= true,
+                Keycode::E => settings.features.extrapolation ^= true,
+                Keycode::B => settings.features.rollback ^= true,
+                Keycode::F3 => settings.net_graph ^= true,
                 Keycode::Plus => settings.increment_ping(),
                 Keycode::Minus => settings.decrement_ping(),
-                _ => (),
+                Keycode::G => toggle_jitter(&mut settings.conditions),
+                Keycode::N => settings.reset_conditions(),
+                _ => adjust_conditions(&mut settings.conditions, kc),
             },
             Ev::KeyDown {
                 keycode: Some(kc),
@@ -118,7 +445,7 @@ fn handle_client_inputs(
             } => match kc {
                 Keycode::Plus => settings.increment_ping(),
                 Keycode::Minus => settings.decrement_ping(),
-                _ => {}
+                _ => adjust_conditions(&mut settings.conditions, kc),
             },
             Ev::KeyUp {
                 keycode: Some(kc),
@@ -136,6 +463,42 @@ fn handle_client_inputs(
     }
 }
 
+/// Adjust the simulated network conditions with the number keys, in pairs of decrease and increase:
+/// `1`/`2` loss, `3`/`4` jitter, `5`/`6` duplication, `7`/`8` reordering and `9`/`0` bandwidth.
+/// Other keys are ignored.
+///
+/// Lowering the bandwidth stops at the tightest cap, since a bandwidth of 0 means no cap.
+pub fn adjust_conditions(conditions: &mut networking::Conditions, kc: Keycode) {
+    const JITTER_INTERVAL: u64 = 10;
+    const PERCENT_INTERVAL: u32 = 1;
+    const BANDWIDTH_INTERVAL: u64 = 16;
+
+    let c = conditions;
+    match kc {
+        Keycode::Num1 => c.loss_percent = c.loss_percent.saturating_sub(PERCENT_INTERVAL),
+        Keycode::Num2 => c.loss_percent = (c.loss_percent + PERCENT_INTERVAL).min(100),
+        Keycode::Num3 => c.jitter_ms = c.jitter_ms.saturating_sub(JITTER_INTERVAL),
+        Keycode::Num4 => c.jitter_ms += JITTER_INTERVAL,
+        Keycode::Num5 => c.duplicate_percent = c.duplicate_percent.saturating_sub(PERCENT_INTERVAL),
+        Keycode::Num6 => c.duplicate_percent = (c.duplicate_percent + PERCENT_INTERVAL).min(100),
+        Keycode::Num7 => c.reorder_percent = c.reorder_percent.saturating_sub(PERCENT_INTERVAL),
+        Keycode::Num8 => c.reorder_percent = (c.reorder_percent + PERCENT_INTERVAL).min(100),
+        Keycode::Num9 if c.bandwidth_kbps > BANDWIDTH_INTERVAL => {
+            c.bandwidth_kbps -= BANDWIDTH_INTERVAL
+        }
+        Keycode::Num0 => c.bandwidth_kbps += BANDWIDTH_INTERVAL,
+        _ => (),
+    }
+}
+
+/// Switch between the uniform and normal jitter distributions.
+pub fn toggle_jitter(conditions: &mut networking::Conditions) {
+    conditions.jitter = match conditions.jitter {
+        networking::Jitter::Uniform => networking::Jitter::Normal,
+        networking::Jitter::Normal => networking::Jitter::Uniform,
+    };
+}
+
 /// Client-observed game state.
 ///
 /// Keeps track of which player the client is, and contains their local version of the game state.
@@ -147,23 +510,129 @@ pub struct State {
     pub shared: Game,
 }
 
+impl State {
+    /// The state of a client which has not joined yet. The level is received from the server.
+    pub fn new(shared: Game) -> Self {
+        Self {
+            player_idx: None,
+            shared: Game {
+                platforms: Vec::new(),
+                ..shared
+            },
+        }
+    }
+}
+
+/// Rate limits a packet which is resent until the server answers it.
+struct Cooldown {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Cooldown {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    /// Returns `true` at most once per interval.
+    fn ready(&mut self) -> bool {
+        let ready = self.last.is_none_or(|t| t.elapsed() >= self.interval);
+        if ready {
+            self.last = Some(Instant::now());
+        }
+        ready
+    }
+}
+
+/// Keeps track of whether the client has the same level as the server.
+struct LevelSync {
+    /// The hash of the level the client has, if any.
+    hash: Option<u64>,
+    /// The hash of the level the server has, as of the last snapshot.
+    server_hash: Option<u64>,
+    requests: Cooldown,
+}
+
+impl LevelSync {
+    fn new() -> Self {
+        Self {
+            hash: None,
+            server_hash: None,
+            requests: Cooldown::new(LEVEL_REQUEST_INTERVAL),
+        }
+    }
+
+    /// Whether the level is missing or outdated, and enough time has passed since the last request.
+    fn should_request(&mut self) -> bool {
+        let mismatch =
+            self.hash.is_none() || self.server_hash.is_some_and(|h| Some(h) != self.hash);
+        mismatch && self.requests.ready()
+    }
+}
+
+/// The mode of the server, and what rollback or lockstep has done, for the overlay.
+struct ModeText<'a>(&'a netcode::Netcode);
+
+impl Display for ModeText<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Mode: {}", self.0.mode())?;
+        if let Some(stats) = self.0.rollback_stats() {
+            write!(f, "\n{stats}")?;
+        }
+        if let Some(stats) = self.0.lockstep_stats() {
+            write!(f, "\n{stats}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The measured round-trip time, its jitter and the clock offset, for the overlay.
+struct ClockText<'a>(&'a netcode::Netcode);
+
+impl Display for ClockText<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.0.rtt(), self.0.clock_offset()) {
+            (Some(rtt), Some(offset)) => write!(
+                f,
+                "Measured RTT: {:?} (jitter {:?})\nClock offset: {:.1} ms",
+                rtt,
+                self.0.rtt_jitter(),
+                offset * 1000.
+            ),
+            _ => write!(f, "Measured RTT: -\nClock offset: -"),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Settings {
-    reconciliation: bool,
-    prediction: bool,
-    interpolation: bool,
-    ping_ms: u64,
+    features: netcode::Features,
+    conditions: networking::Conditions,
+    /// Show the net graph instead of the text.
+    net_graph: bool,
 }
 
 impl Settings {
     const PING_INTERVAL: u64 = 50;
 
     fn increment_ping(&mut self) {
-        self.ping_ms += Self::PING_INTERVAL;
+        self.conditions.ping_ms += Self::PING_INTERVAL;
     }
 
     fn decrement_ping(&mut self) {
-        self.ping_ms -= Self::PING_INTERVAL.min(self.ping_ms);
+        let ping_ms = &mut self.conditions.ping_ms;
+        *ping_ms -= Self::PING_INTERVAL.min(*ping_ms);
+    }
+
+    /// Turn off the simulated network conditions, except the ping, which is adjusted separately.
+    fn reset_conditions(&mut self) {
+        self.conditions = networking::Conditions {
+            ping_ms: self.conditions.ping_ms,
+            ..networking::Conditions::NONE
+        };
     }
 }
 
@@ -171,11 +640,13 @@ impl Display for Settings {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Reconciliation: {}\nInterpolation: {}\nPrediction: {}\nPing: {:?}",
-            self.reconciliation,
-            self.interpolation,
-            self.prediction,
-            Duration::from_millis(self.ping_ms)
+            "Reconciliation: {}\nInterpolation: {}\nPrediction: {}\nExtrapolation: {}\nRollback: {}\n{}",
+            self.features.reconciliation,
+            self.features.interpolation,
+            self.features.prediction,
+            self.features.extrapolation,
+            self.features.rollback,
+            self.conditions
         )
     }
 }
@@ -183,40 +654,161 @@ impl Display for Settings {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{interpolation::Underrun, networking::Codec};
+
+    fn settings(ping_ms: u64) -> Settings {
+        Settings {
+            features: netcode::Features::default(),
+            conditions: networking::Conditions {
+                ping_ms,
+                ..networking::Conditions::NONE
+            },
+            net_graph: false,
+        }
+    }
 
     #[test]
-    fn ping_increment() {
-        let mut settings = Settings {
-            reconciliation: false,
-            prediction: false,
-            interpolation: false,
-            ping_ms: 250,
+    fn duplicate_and_stale_snapshots_do_not_replace_the_state() {
+        let server = std::net::UdpSocket::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let codec = networking::Binary;
+        let conditions = networking::Conditions::NONE;
+        let mut session =
+            Session::connect(&codec, server.local_addr().unwrap(), conditions).unwrap();
+        let mut state = State::new(Game::new());
+        let mut netcode = netcode::init(None, Underrun::Hold, netcode::DEFAULT_SMOOTHING);
+
+        // learn the address of the client from its connection request
+        session.send(&state, &mut netcode, (0, 0)).unwrap();
+        let (_, client) = server.recv_from(&mut [0; 64]).unwrap();
+
+        for (tick, ack_id) in [(2, 20), (2, 20), (1, 10)] {
+            let response = ServerResponse {
+                ack_id,
+                player_hash: None,
+                level_hash: 0,
+                sequence: tick as usize,
+                tick,
+                time_us: tick * 16_667,
+                input: InputStatus {
+                    depth: tick as usize,
+                    ..InputStatus::default()
+                },
+                snapshot: Snapshot::Full(Vec::new()),
+            };
+            let bytes = codec.encode_server(&ServerPacket::Snapshot(response));
+            server.send_to(&bytes, client).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+
+        let received = session.receive(&mut state, &mut netcode).unwrap();
+        assert_eq!(received.snapshots, 1);
+        assert_eq!(received.move_ack_id, 20);
+        assert_eq!(session.latest_sequence, 2);
+        assert_eq!(session.input_status().depth, 2);
+        let counters = SnapshotCounters {
+            applied: 1,
+            duplicate: 1,
+            stale: 1,
+            undecodable: 0,
         };
+        assert_eq!(session.counters(), counters);
+    }
+
+    #[test]
+    fn frame_time_keeps_the_input_buffer_at_its_target() {
+        let server = std::net::UdpSocket::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let codec = networking::Binary;
+        let mut session = Session::connect(
+            &codec,
+            server.local_addr().unwrap(),
+            networking::Conditions::NONE,
+        )
+        .unwrap();
+        assert_eq!(session.frame_time(), FRAME_TIME);
+
+        session.input = InputStatus {
+            depth: 1,
+            target: 2,
+            ..InputStatus::default()
+        };
+        assert!(
+            session.frame_time() < FRAME_TIME,
+            "too shallow, so send faster"
+        );
+
+        session.input.depth = 40;
+        let slowest = FRAME_TIME.mul_f64(1. + MAX_RATE_ADJUSTMENT);
+        assert_eq!(session.frame_time(), slowest);
+    }
+
+    #[test]
+    fn level_requested_until_hashes_match() {
+        let mut level = LevelSync::new();
+        assert!(level.should_request());
+        assert!(!level.should_request(), "requests are rate limited");
+
+        level.hash = Some(1);
+        level.server_hash = Some(1);
+        level.requests = Cooldown::new(Duration::ZERO);
+        assert!(!level.should_request());
+
+        level.server_hash = Some(2);
+        assert!(level.should_request());
+    }
+
+    #[test]
+    fn ping_increment() {
+        let mut settings = settings(250);
         settings.increment_ping();
-        assert_eq!(settings.ping_ms, 300);
+        assert_eq!(settings.conditions.ping_ms, 300);
     }
 
     #[test]
     fn ping_decrement() {
-        let mut settings = Settings {
-            reconciliation: false,
-            prediction: false,
-            interpolation: false,
-            ping_ms: 250,
-        };
+        let mut settings = settings(250);
         settings.decrement_ping();
-        assert_eq!(settings.ping_ms, 200);
+        assert_eq!(settings.conditions.ping_ms, 200);
     }
 
     #[test]
     fn ping_decrement_zero() {
-        let mut settings = Settings {
-            reconciliation: false,
-            prediction: false,
-            interpolation: false,
-            ping_ms: 0,
-        };
+        let mut settings = settings(0);
         settings.decrement_ping();
-        assert_eq!(settings.ping_ms, 0);
+        assert_eq!(settings.conditions.ping_ms, 0);
+    }
+
+    #[test]
+    fn reset_conditions_keeps_the_ping() {
+        let mut settings = settings(250);
+        adjust_conditions(&mut settings.conditions, Keycode::Num2);
+        settings.reset_conditions();
+        assert_eq!(
+            settings.conditions,
+            networking::Conditions {
+                ping_ms: 250,
+                ..networking::Conditions::NONE
+            }
+        );
+    }
+
+    #[test]
+    fn conditions_adjusted_with_number_keys() {
+        let mut conditions = networking::Conditions::NONE;
+        for kc in [Keycode::Num2, Keycode::Num4, Keycode::Num0, Keycode::Num5] {
+            adjust_conditions(&mut conditions, kc);
+        }
+        let adjusted = conditions;
+        adjust_conditions(&mut conditions, Keycode::W);
+        assert_eq!(conditions, adjusted, "other keys are ignored");
+
+        assert_eq!(conditions.loss_percent, 1);
+        assert_eq!(conditions.jitter_ms, 10);
+        assert_eq!(conditions.bandwidth_kbps, 16);
+        adjust_conditions(&mut conditions, Keycode::Num9);
+        assert_eq!(conditions.bandwidth_kbps, 16, "the cap stays on");
+        assert_eq!(conditions.duplicate_percent, 0);
+
+        toggle_jitter(&mut conditions);
+        assert_eq!(conditions.jitter, networking::Jitter::Normal);
     }
 }
@@ -0,0 +1,179 @@
+//! Synchronization of the client's clock with the server's, by NTP-style ping/pong exchanges.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Number of recent exchanges the clock offset is chosen from.
+const WINDOW: usize = 8;
+
+/// Weight of each new measurement of the round-trip time and jitter.
+const SMOOTHING: f64 = 0.125;
+
+/// Estimates the round-trip time to the server, and the offset of the server's clock from the client's.
+///
+/// The client sends its own time in a ping, and the server answers with the same time and its own.
+/// Assuming the server's time was taken halfway through the exchange,
+/// the offset is the server's time minus the middle of the exchange on the client.
+/// Exchanges delayed by queuing are less precise, so the offset is taken from the exchange
+/// with the smallest round-trip time among the last few.
+pub struct ClockSync {
+    /// The origin of the client's time.
+    epoch: Instant,
+    samples: VecDeque<Sample>,
+    /// The client time of the newest ping answered, used to discard stale and duplicate pongs.
+    newest: Option<u64>,
+    /// Smoothed round-trip time, in seconds.
+    rtt: f64,
+    /// Smoothed deviation of the round-trip time, in seconds.
+    jitter: f64,
+}
+
+struct Sample {
+    /// Round-trip time in seconds.
+    rtt: f64,
+    /// Server time minus client time, in seconds.
+    offset: f64,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            samples: VecDeque::with_capacity(WINDOW),
+            newest: None,
+            rtt: 0.,
+            jitter: 0.,
+        }
+    }
+
+    /// The client's time at `now`, in microseconds, as sent in pings.
+    pub fn client_time(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_micros() as u64
+    }
+
+    /// Add an exchange: a ping sent at client time `sent_us`, answered at server time `server_us`,
+    /// whose pong arrived at `now`.
+    ///
+    /// Pongs older than the newest one answered, including duplicates, are ignored.
+    pub fn pong(&mut self, sent_us: u64, server_us: u64, now: Instant) {
+        let received_us = self.client_time(now);
+        if self.newest.is_some_and(|newest| sent_us <= newest) || sent_us > received_us {
+            return;
+        }
+        self.newest = Some(sent_us);
+
+        let rtt = (received_us - sent_us) as f64 / 1e6;
+        let middle = (sent_us + received_us) as f64 / 2e6;
+        if self.samples.is_empty() {
+            self.rtt = rtt;
+        } else {
+            self.jitter += SMOOTHING * ((rtt - self.rtt).abs() - self.jitter);
+            self.rtt += SMOOTHING * (rtt - self.rtt);
+        }
+
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            rtt,
+            offset: server_us as f64 / 1e6 - middle,
+        });
+    }
+
+    /// The smoothed round-trip time, or `None` before the first pong.
+    pub fn rtt(&self) -> Option<Duration> {
+        (!self.samples.is_empty()).then(|| Duration::from_secs_f64(self.rtt))
+    }
+
+    /// The smoothed variation of the round-trip time.
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    /// The server's clock minus the client's, in seconds, or `None` before the first pong.
+    pub fn offset(&self) -> Option<f64> {
+        self.best().map(|best| best.offset)
+    }
+
+    /// The estimated server time at `now`, in seconds, or `None` before the first pong.
+    pub fn server_time(&self, now: Instant) -> Option<f64> {
+        Some(self.client_time(now) as f64 / 1e6 + self.offset()?)
+    }
+
+    /// The estimated server time at which a packet arriving at `now` was sent, in seconds.
+    ///
+    /// Unlike half the round-trip time, this is exact even if the delay differs between the directions,
+    /// since the error in the offset cancels out.
+    pub fn sent_time(&self, now: Instant) -> Option<f64> {
+        let best = self.best()?;
+        Some(self.client_time(now) as f64 / 1e6 + best.offset - best.rtt / 2.)
+    }
+
+    /// The exchange with the smallest round-trip time among the last few.
+    fn best(&self) -> Option<&Sample> {
+        self.samples.iter().min_by(|a, b| a.rtt.total_cmp(&b.rtt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exchange a ping at client time `sent_ms`, which takes `up_ms` to reach the server and `down_ms` back,
+    /// with the server's clock `offset_ms` ahead of the client's.
+    fn exchange(clock: &mut ClockSync, sent_ms: u64, up_ms: u64, down_ms: u64, offset_ms: u64) {
+        let now = clock.epoch + Duration::from_millis(sent_ms + up_ms + down_ms);
+        let server_us = (sent_ms + up_ms + offset_ms) * 1000;
+        clock.pong(sent_ms * 1000, server_us, now);
+    }
+
+    #[test]
+    fn symmetric_delay_gives_exact_offset() {
+        let mut clock = ClockSync::new();
+        assert_eq!(clock.offset(), None);
+
+        for i in 0..4 {
+            exchange(&mut clock, i * 100, 25, 25, 5_000);
+        }
+        assert!((clock.offset().unwrap() - 5.).abs() < 1e-9);
+        let now = clock.epoch + Duration::from_millis(1_000);
+        assert!((clock.server_time(now).unwrap() - 6.).abs() < 1e-9);
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(50)));
+        assert_eq!(clock.jitter(), Duration::ZERO);
+    }
+
+    #[test]
+    fn offset_is_taken_from_the_fastest_exchange() {
+        let mut clock = ClockSync::new();
+        exchange(&mut clock, 0, 25, 25, 1_000);
+        // queued on the way to the server, which skews the offset of this exchange
+        exchange(&mut clock, 100, 125, 25, 1_000);
+
+        assert!((clock.offset().unwrap() - 1.).abs() < 1e-9);
+        assert!(clock.rtt().unwrap() > Duration::from_millis(50));
+        assert!(clock.jitter() > Duration::ZERO);
+    }
+
+    #[test]
+    fn sent_time_is_exact_with_asymmetric_delay() {
+        let mut clock = ClockSync::new();
+        exchange(&mut clock, 0, 10, 40, 2_000);
+
+        // a packet sent by the server at 2.2 s takes 40 ms to arrive
+        let now = clock.epoch + Duration::from_millis(240);
+        assert!((clock.sent_time(now).unwrap() - 2.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stale_and_duplicate_pongs_are_ignored() {
+        let mut clock = ClockSync::new();
+        exchange(&mut clock, 100, 25, 25, 0);
+        exchange(&mut clock, 100, 25, 500, 0);
+        exchange(&mut clock, 50, 25, 500, 0);
+
+        assert_eq!(clock.samples.len(), 1);
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(50)));
+    }
+}
@@ -0,0 +1,455 @@
+//! Settings from the command line and an optional JSON config file.
+
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use crate::{
+    bot, client,
+    interpolation::Underrun,
+    model::{MAX_PLAYERS, Mode, Physics},
+    netcode, networking, server, sys,
+};
+
+/// The address of the server, unless configured.
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 7878;
+
+/// Printed by `--help`.
+pub const USAGE: &str = "\
+usage: netcode [client|server|bot] [options]
+
+options:
+  --config <file>      read settings from a JSON config file, before the other options
+  --host <host>        address of the server (default 127.0.0.1)
+  --port <port>        port of the server (default 7878)
+  --codec <name>       packet format, binary or json (default binary)
+  --scale <n>          window scale (default 4)
+
+server options:
+  --tickrate <n>       snapshots per second (default 4)
+  --headless           run without a window
+  --max-players <n>    players allowed at the same time (default 6)
+  --timeout <secs>     time before silent clients are dropped (default 5)
+  --input-buffer <n>   inputs buffered per client to absorb jitter (default 2)
+  --mode <name>        authoritative, rollback or lockstep (default authoritative)
+  --physics <name>     float or fixed, which is deterministic across platforms (default float)
+
+client and bot options:
+  --ping <ms>          simulated round-trip time (default 250)
+  --prediction         enable prediction
+  --reconciliation     enable reconciliation
+  --interpolation      enable interpolation
+  --extrapolation      enable extrapolation
+  --rollback           enable rollback, when the server is in rollback mode
+  --interp-delay <ms>  fixed interpolation delay, or auto to adapt it to the jitter (default auto)
+  --underrun <mode>    when interpolation runs out of snapshots: hold or linear (default hold)
+  --smoothing <ms>     time to smooth out corrections of the local player, 0 to disable (default 100)
+  --snap-distance <n>  corrections larger than this are not smoothed (default 32)
+  --redundancy <n>     earlier unacknowledged inputs resent with each input (default 8, at most 64)
+  --net-graph          show the net graph instead of the text (toggle with F3)
+
+bot options:
+  --bots <n>           number of bots (default 1)
+  --pattern <name>     idle, walk, jump or random (default random)
+  --duration <secs>    how long the bots play (default 10)";
+
+/// Which part of the demo to run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Client,
+    Server,
+    Bot,
+    /// Print the usage and exit.
+    Help,
+}
+
+/// All settings, read from the config file if one is given and then overridden by the command line.
+///
+/// Fields which are missing from the config file keep their default values.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address the server listens on, and clients connect to.
+    pub host: String,
+    pub port: u16,
+    /// The name of the packet format, see `networking::codec`.
+    pub codec: String,
+    /// Window scale, for both the server and the client.
+    pub scale: u32,
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+    pub bot: BotConfig,
+}
+
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Snapshots broadcast per second, at start-up.
+    pub tickrate: usize,
+    /// Run without a window.
+    pub headless: bool,
+    /// Players allowed at the same time, at most `MAX_PLAYERS`.
+    pub max_players: usize,
+    /// Seconds without any packets from a client before it is considered disconnected.
+    pub timeout_secs: f64,
+    /// Inputs buffered per client before they are simulated.
+    pub input_buffer: usize,
+    /// How the game is simulated and kept in sync.
+    pub mode: Mode,
+    /// Which arithmetic the physics use.
+    pub physics: Physics,
+}
+
+/// The initial settings of the client, which can be changed while it runs. Also used by bots.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Simulated round-trip time.
+    pub ping_ms: u64,
+    pub prediction: bool,
+    pub reconciliation: bool,
+    pub interpolation: bool,
+    pub extrapolation: bool,
+    /// Show the game simulated with rollback, rather than the server's final states, in `Mode::Rollback`.
+    pub rollback: bool,
+    /// A fixed interpolation delay, or `None` to adapt it to the measured jitter.
+    pub interp_delay_ms: Option<u64>,
+    /// What interpolation shows when it runs out of snapshots.
+    pub underrun: Underrun,
+    /// Time to smooth out corrections of the local player, or 0 to show them immediately.
+    pub smoothing_ms: u64,
+    /// Corrections larger than this are shown immediately.
+    pub snap_distance: f64,
+    /// Earlier unacknowledged movements sent along with each movement, in case of packet loss.
+    pub redundancy: usize,
+    /// Show the net graph of network measurements over time.
+    pub net_graph: bool,
+}
+
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    pub count: usize,
+    pub pattern: bot::Pattern,
+    /// How long the bots play, in seconds.
+    pub duration_secs: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: String::from(DEFAULT_HOST),
+            port: DEFAULT_PORT,
+            codec: String::from("binary"),
+            scale: sys::DEFAULT_SCALE,
+            server: ServerConfig::default(),
+            client: ClientConfig::default(),
+            bot: BotConfig::default(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tickrate: server::DEFAULT_TICKRATE,
+            headless: false,
+            max_players: MAX_PLAYERS,
+            timeout_secs: server::DEFAULT_TIMEOUT.as_secs_f64(),
+            input_buffer: server::DEFAULT_INPUT_BUFFER,
+            mode: Mode::Authoritative,
+            physics: Physics::Float,
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            ping_ms: client::DEFAULT_PING_MS,
+            prediction: false,
+            reconciliation: false,
+            interpolation: false,
+            extrapolation: false,
+            rollback: false,
+            interp_delay_ms: None,
+            underrun: Underrun::Hold,
+            smoothing_ms: netcode::DEFAULT_SMOOTHING.time.as_millis() as u64,
+            snap_distance: netcode::DEFAULT_SMOOTHING.snap_distance,
+            redundancy: client::DEFAULT_REDUNDANCY,
+            net_graph: false,
+        }
+    }
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            pattern: bot::Pattern::Random,
+            duration_secs: bot::DEFAULT_DURATION.as_secs_f64(),
+        }
+    }
+}
+
+impl Config {
+    /// Parse a config file.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    /// The address of the server.
+    pub fn addr(&self) -> (&str, u16) {
+        (&self.host, self.port)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if networking::codec(&self.codec).is_none() {
+            return Err(format!("unknown codec: {}", self.codec));
+        }
+        if self.scale == 0 {
+            return Err(String::from("the scale must be at least 1"));
+        }
+        if self.server.tickrate == 0 {
+            return Err(String::from("the tick rate must be at least 1"));
+        }
+        if !(1..=MAX_PLAYERS).contains(&self.server.max_players) {
+            return Err(format!("max players must be between 1 and {MAX_PLAYERS}"));
+        }
+        if !(0..=client::MAX_REDUNDANCY).contains(&self.client.redundancy) {
+            return Err(format!(
+                "the redundancy must be between 0 and {}",
+                client::MAX_REDUNDANCY
+            ));
+        }
+        let timeout = Duration::try_from_secs_f64(self.server.timeout_secs)
+            .map_err(|e| format!("invalid timeout: {e}"))?;
+        if timeout.is_zero() {
+            return Err(String::from("the timeout must be positive"));
+        }
+        let snap_distance = self.client.snap_distance;
+        if !snap_distance.is_finite() || snap_distance < 0. {
+            return Err(String::from("the snap distance can not be negative"));
+        }
+        Duration::try_from_secs_f64(self.bot.duration_secs)
+            .map_err(|e| format!("invalid bot duration: {e}"))?;
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.timeout_secs)
+    }
+}
+
+impl ClientConfig {
+    pub fn features(&self) -> netcode::Features {
+        netcode::Features {
+            prediction: self.prediction,
+            reconciliation: self.reconciliation,
+            interpolation: self.interpolation,
+            extrapolation: self.extrapolation,
+            rollback: self.rollback,
+        }
+    }
+
+    pub fn interpolation_delay(&self) -> Option<Duration> {
+        self.interp_delay_ms.map(Duration::from_millis)
+    }
+
+    pub fn smoothing(&self) -> netcode::Smoothing {
+        netcode::Smoothing {
+            time: Duration::from_millis(self.smoothing_ms),
+            snap_distance: self.snap_distance,
+        }
+    }
+}
+
+impl BotConfig {
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.duration_secs)
+    }
+}
+
+/// Parse the command line arguments, without the program name.
+///
+/// A config file given with `--config` is read first, so that the other options override it.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<(Command, Config), String> {
+    let args: Vec<String> = args.into_iter().collect();
+
+    let mut config = match args.iter().position(|a| a == "--config") {
+        Some(i) => {
+            let path = args.get(i + 1).ok_or("missing value for --config")?;
+            let json = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+            Config::from_json(&json).map_err(|e| format!("{path}: {e}"))?
+        }
+        None => Config::default(),
+    };
+
+    let mut command = Command::Client;
+    let mut headless = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let args = &mut args;
+        match &arg[..] {
+            "client" => command = Command::Client,
+            "server" | "--server" => command = Command::Server,
+            "bot" => command = Command::Bot,
+            "--help" | "-h" => return Ok((Command::Help, config)),
+            // already read
+            "--config" => {
+                args.next();
+            }
+            "--host" => config.host = value(args, &arg)?,
+            "--port" => config.port = value(args, &arg)?,
+            "--codec" => config.codec = value(args, &arg)?,
+            "--scale" => config.scale = value(args, &arg)?,
+            "--tickrate" => config.server.tickrate = value(args, &arg)?,
+            "--headless" => headless = true,
+            "--max-players" => config.server.max_players = value(args, &arg)?,
+            "--timeout" => config.server.timeout_secs = value(args, &arg)?,
+            "--input-buffer" => config.server.input_buffer = value(args, &arg)?,
+            "--mode" => config.server.mode = value(args, &arg)?,
+            "--physics" => config.server.physics = value(args, &arg)?,
+            "--ping" => config.client.ping_ms = value(args, &arg)?,
+            "--prediction" => config.client.prediction = true,
+            "--reconciliation" => config.client.reconciliation = true,
+            "--interpolation" => config.client.interpolation = true,
+            "--extrapolation" => config.client.extrapolation = true,
+            "--rollback" => config.client.rollback = true,
+            "--interp-delay" => {
+                config.client.interp_delay_ms = match &value::<String>(args, &arg)?[..] {
+                    "auto" => None,
+                    ms => Some(
+                        ms.parse()
+                            .map_err(|e| format!("invalid value for {arg}: {ms} ({e})"))?,
+                    ),
+                }
+            }
+            "--underrun" => config.client.underrun = value(args, &arg)?,
+            "--smoothing" => config.client.smoothing_ms = value(args, &arg)?,
+            "--snap-distance" => config.client.snap_distance = value(args, &arg)?,
+            "--redundancy" => config.client.redundancy = value(args, &arg)?,
+            "--net-graph" => config.client.net_graph = true,
+            "--bots" => config.bot.count = value(args, &arg)?,
+            "--pattern" => config.bot.pattern = value(args, &arg)?,
+            "--duration" => config.bot.duration_secs = value(args, &arg)?,
+            _ => return Err(format!("unknown argument: {arg}\n\n{USAGE}")),
+        }
+    }
+
+    if headless {
+        if command != Command::Server {
+            return Err(String::from("only the server can run headless"));
+        }
+        config.server.headless = true;
+    }
+    config.validate()?;
+
+    Ok((command, config))
+}
+
+/// Parse the value following an option.
+fn value<T>(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let arg = args.next().ok_or(format!("missing value for {flag}"))?;
+    arg.parse()
+        .map_err(|e| format!("invalid value for {flag}: {arg} ({e})"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<(Command, Config), String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        assert_eq!(parse_str(""), Ok((Command::Client, Config::default())));
+    }
+
+    #[test]
+    fn options_override_defaults() {
+        let (command, config) = parse_str(
+            "server --host 10.0.0.2 --port 9000 --tickrate 20 --headless --max-players 2 --input-buffer 4 --mode rollback",
+        )
+        .unwrap();
+        assert_eq!(command, Command::Server);
+        assert_eq!(config.addr(), ("10.0.0.2", 9000));
+        assert_eq!(config.server.tickrate, 20);
+        assert!(config.server.headless);
+        assert_eq!(config.server.max_players, 2);
+        assert_eq!(config.server.input_buffer, 4);
+        assert_eq!(config.server.mode, Mode::Rollback);
+
+        let (command, config) =
+            parse_str("bot --bots 12 --pattern walk --ping 80 --prediction --rollback").unwrap();
+        assert_eq!(command, Command::Bot);
+        assert_eq!(config.bot.count, 12);
+        assert_eq!(config.bot.pattern, bot::Pattern::Walk);
+        assert_eq!(config.client.ping_ms, 80);
+        assert!(config.client.prediction);
+        assert!(config.client.rollback);
+
+        let (_, config) =
+            parse_str("--interp-delay 120 --underrun linear --redundancy 0 --net-graph").unwrap();
+        assert_eq!(config.client.redundancy, 0);
+        assert!(parse_str(&format!("--redundancy {}", client::MAX_REDUNDANCY)).is_ok());
+        assert!(parse_str(&format!("--redundancy {}", client::MAX_REDUNDANCY + 1)).is_err());
+        assert!(config.client.net_graph);
+        assert_eq!(
+            config.client.interpolation_delay(),
+            Some(Duration::from_millis(120))
+        );
+        assert_eq!(config.client.underrun, Underrun::Linear);
+        let (_, config) = parse_str("--interp-delay 120 --interp-delay auto").unwrap();
+        assert_eq!(config.client.interpolation_delay(), None);
+        assert!(!config.client.interpolation);
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(parse_str("--port").is_err());
+        assert!(parse_str("--port high").is_err());
+        assert!(parse_str("--fast").is_err());
+        assert!(parse_str("--codec xml").is_err());
+        assert!(parse_str("server --mode p2p").is_err());
+        let (_, config) = parse_str("server --mode lockstep").unwrap();
+        assert_eq!(config.server.mode, Mode::Lockstep);
+        assert!(parse_str("server --physics double").is_err());
+        let (_, config) = parse_str("server --physics fixed").unwrap();
+        assert_eq!(config.server.physics, Physics::Fixed);
+        assert!(parse_str("server --max-players 7").is_err());
+        assert!(parse_str("client --headless").is_err());
+        assert!(parse_str("server --timeout 0").is_err());
+        assert!(parse_str("server --timeout 1e300").is_err());
+        assert!(parse_str("bot --duration -1").is_err());
+        assert!(parse_str("bot --duration 1e300").is_err());
+    }
+
+    #[test]
+    fn config_file_is_overridden_by_options() {
+        let config = Config::from_json(
+            r#"{ "host": "example.com", "server": { "tickrate": 30 }, "bot": { "pattern": "jump" } }"#,
+        )
+        .unwrap();
+        assert_eq!(config.addr(), ("example.com", DEFAULT_PORT));
+        assert_eq!(config.server.tickrate, 30);
+        assert_eq!(config.server.max_players, MAX_PLAYERS);
+        assert_eq!(config.bot.pattern, bot::Pattern::Jump);
+
+        let path = std::env::temp_dir().join("netcode-config-test.json");
+        std::fs::write(&path, r#"{ "port": 9001, "client": { "ping_ms": 0 } }"#).unwrap();
+        let args = format!("--config {} --port 9002", path.display());
+        let (_, config) = parse_str(&args).unwrap();
+        assert_eq!(config.port, 9002);
+        assert_eq!(config.client.ping_ms, 0);
+
+        assert!(Config::from_json(r#"{ "prot": 1 }"#).is_err());
+    }
+}
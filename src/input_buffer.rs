@@ -0,0 +1,166 @@
+//! A buffer of inputs from one client, consumed by the server a small depth behind their arrival.
+
+use std::collections::VecDeque;
+
+use crate::model::{InputStatus, Message, Movement};
+
+/// Upper bound on inputs buffered per client. Older inputs are dropped when a client floods the server.
+const MAX_QUEUED_INPUTS: usize = 32;
+
+/// Inputs waiting to be simulated, ordered by id.
+///
+/// Consumption waits until the buffer holds the target number of inputs,
+/// so that inputs arriving in bursts or with jitter are still simulated one per tick.
+/// When the buffer runs dry, it refills to the target before inputs are consumed again.
+pub struct InputBuffer {
+    inputs: VecDeque<Movement>,
+    /// The id of the newest movement received, so that redundant copies of movements are only queued once.
+    last_received: usize,
+    target: usize,
+    /// Whether the buffer is refilling to the target after starting or running dry.
+    refilling: bool,
+    underruns: usize,
+    overruns: usize,
+}
+
+impl InputBuffer {
+    pub fn new(target: usize) -> Self {
+        Self {
+            inputs: VecDeque::new(),
+            last_received: 0,
+            target,
+            refilling: true,
+            underruns: 0,
+            overruns: 0,
+        }
+    }
+
+    /// Queue the movements of a message which have not been received before.
+    pub fn push(&mut self, message: &Message) {
+        for movement in message.movements() {
+            if movement.id <= self.last_received {
+                continue;
+            }
+            self.last_received = movement.id;
+            if self.inputs.len() >= MAX_QUEUED_INPUTS {
+                self.inputs.pop_front();
+                self.overruns += 1;
+            }
+            self.inputs.push_back(movement);
+        }
+    }
+
+    /// The next input to simulate, or `None` if the buffer is empty or refilling.
+    pub fn pop(&mut self) -> Option<Movement> {
+        if self.refilling {
+            if self.inputs.len() < self.target.max(1) {
+                return None;
+            }
+            self.refilling = false;
+        }
+
+        let movement = self.inputs.pop_front();
+        if movement.is_none() {
+            self.underruns += 1;
+            self.refilling = true;
+        }
+        movement
+    }
+
+    /// The state of the buffer, as reported to the client.
+    pub fn status(&self) -> InputStatus {
+        InputStatus {
+            depth: self.inputs.len(),
+            target: self.target,
+            underruns: self.underruns,
+            overruns: self.overruns,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: usize) -> Message {
+        Message {
+            id,
+            x: 1,
+            y: 0,
+            snapshot_ack: 0,
+            redundant: Vec::new(),
+        }
+    }
+
+    fn drain(buffer: &mut InputBuffer) -> Vec<Movement> {
+        std::iter::from_fn(|| buffer.pop()).collect()
+    }
+
+    #[test]
+    fn waits_for_the_target_depth() {
+        let mut buffer = InputBuffer::new(3);
+        buffer.push(&message(1));
+        buffer.push(&message(2));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(
+            buffer.status().underruns,
+            0,
+            "filling up is not an underrun"
+        );
+
+        buffer.push(&message(3));
+        assert_eq!(buffer.pop().map(|m| m.id), Some(1));
+        assert_eq!(buffer.pop().map(|m| m.id), Some(2));
+        assert_eq!(buffer.pop().map(|m| m.id), Some(3));
+    }
+
+    #[test]
+    fn underruns_refill_before_consuming_again() {
+        let mut buffer = InputBuffer::new(2);
+        buffer.push(&message(1));
+        buffer.push(&message(2));
+        buffer.pop();
+        buffer.pop();
+
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.status().underruns, 1);
+
+        buffer.push(&message(3));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.status().underruns, 1);
+        buffer.push(&message(4));
+        assert_eq!(buffer.pop().map(|m| m.id), Some(3));
+    }
+
+    #[test]
+    fn redundant_inputs_fill_gaps_and_are_queued_once() {
+        let mut buffer = InputBuffer::new(1);
+        buffer.push(&message(1));
+        // message 2 was lost, and message 4 arrives before message 3
+        let message = |id, redundant: &[(i8, i8)]| Message {
+            redundant: redundant.to_vec(),
+            ..message(id)
+        };
+        buffer.push(&message(4, &[(0, 0), (-1, 0), (1, 0)]));
+        buffer.push(&message(3, &[(-1, 0), (1, 0)]));
+
+        let movements = drain(&mut buffer);
+        let ids: Vec<_> = movements.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        assert_eq!(movements[1].dir, (-1, 0));
+        assert_eq!(buffer.last_received, 4);
+    }
+
+    #[test]
+    fn floods_overrun_the_buffer() {
+        let mut buffer = InputBuffer::new(2);
+        for id in 1..=MAX_QUEUED_INPUTS + 3 {
+            buffer.push(&message(id));
+        }
+
+        let status = buffer.status();
+        assert_eq!(status.depth, MAX_QUEUED_INPUTS);
+        assert_eq!(status.overruns, 3);
+        assert_eq!(buffer.pop().map(|m| m.id), Some(4));
+    }
+}
@@ -0,0 +1,375 @@
+//! A buffer of snapshots for interpolating remote players, rendered a small delay behind the server.
+
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::model::Player;
+
+/// Number of snapshots kept in the buffer.
+const BUFFER_LEN: usize = 32;
+
+/// Initial guess of the time between snapshots, until it has been measured.
+const INITIAL_INTERVAL: f64 = 0.1;
+
+/// Weight of each new measurement of the snapshot interval and jitter.
+const SMOOTHING: f64 = 0.1;
+
+/// The adaptive delay covers this many times the measured jitter.
+const JITTER_MARGIN: f64 = 2.;
+
+/// Extra adaptive delay, so that snapshots arriving on time never leave the buffer dry.
+const SAFETY_MARGIN: f64 = 0.02;
+
+/// Maximum change in playback speed while catching up with the target delay.
+const MAX_DRIFT: f64 = 0.1;
+
+/// Playback jumps to the target if it is further behind or ahead than this many snapshots.
+const MAX_LAG: f64 = 3.;
+
+/// Snapshots a `Linear` underrun continues past the newest snapshot.
+const MAX_LINEAR: f64 = 1.;
+
+/// What to show when playback has passed the newest snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Underrun {
+    /// Hold remote players at the newest snapshot.
+    Hold,
+    /// Continue the movement between the two newest snapshots, for at most one more snapshot.
+    Linear,
+}
+
+impl FromStr for Underrun {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hold" => Ok(Underrun::Hold),
+            "linear" => Ok(Underrun::Linear),
+            _ => Err(format!("unknown underrun mode: {s}")),
+        }
+    }
+}
+
+/// Snapshots ordered by server tick, played back on the server's timeline at a delay behind the newest one.
+///
+/// Playback speeds up or slows down slightly to stay at the delay,
+/// so that bursts and jitter in the arrival of snapshots do not show.
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Buffered>,
+    /// The tick and server time of the newest snapshot, and when it arrived.
+    newest: Option<(u64, f64, Instant)>,
+    /// Measured server time between snapshots, in seconds.
+    interval: f64,
+    /// Measured variation in the arrival of snapshots, compared to when they were sent, in seconds.
+    jitter: f64,
+    samples: usize,
+    /// The server time being shown, and when it was last advanced.
+    playhead: Option<(f64, Instant)>,
+    /// A fixed delay, or `None` to adapt it to the jitter.
+    fixed_delay: Option<Duration>,
+    underrun: Underrun,
+    underruns: usize,
+    /// How far the last sample was past the newest snapshot.
+    overshoot: Duration,
+}
+
+struct Buffered {
+    tick: u64,
+    /// Server time in seconds.
+    time: f64,
+    players: Vec<Option<Player>>,
+}
+
+impl SnapshotBuffer {
+    pub fn new(fixed_delay: Option<Duration>, underrun: Underrun) -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(BUFFER_LEN),
+            newest: None,
+            interval: INITIAL_INTERVAL,
+            jitter: 0.,
+            samples: 0,
+            playhead: None,
+            fixed_delay,
+            underrun,
+            underruns: 0,
+            overshoot: Duration::ZERO,
+        }
+    }
+
+    /// Add a snapshot of server `tick`, sent at server `time`, which arrived at `now`.
+    ///
+    /// Duplicates and snapshots older than the buffer are ignored.
+    pub fn push(&mut self, tick: u64, time: Duration, players: Vec<Option<Player>>, now: Instant) {
+        let time = time.as_secs_f64();
+        let i = self.snapshots.partition_point(|s| s.tick < tick);
+        let duplicate = self.snapshots.get(i).is_some_and(|s| s.tick == tick);
+        let too_old = i == 0 && self.snapshots.len() == BUFFER_LEN;
+        if duplicate || too_old {
+            return;
+        }
+        self.snapshots.insert(
+            i,
+            Buffered {
+                tick,
+                time,
+                players,
+            },
+        );
+        if self.snapshots.len() > BUFFER_LEN {
+            self.snapshots.pop_front();
+        }
+
+        match self.newest {
+            Some((newest, newest_time, arrival)) if tick > newest => {
+                let sent = time - newest_time;
+                let received = (now - arrival).as_secs_f64();
+                self.samples += 1;
+                let weight = SMOOTHING.max(1. / self.samples as f64);
+                self.jitter += weight * ((received - sent).abs() - self.jitter);
+                self.interval += weight * (sent - self.interval);
+                self.newest = Some((tick, time, now));
+            }
+            Some(_) => (),
+            None => self.newest = Some((tick, time, now)),
+        }
+    }
+
+    /// How far behind the newest snapshot remote players are shown.
+    pub fn delay(&self) -> Duration {
+        self.fixed_delay.unwrap_or_else(|| {
+            Duration::from_secs_f64(self.interval + JITTER_MARGIN * self.jitter + SAFETY_MARGIN)
+        })
+    }
+
+    /// The number of times playback has passed the newest snapshot.
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
+    /// How far the last sample was past the newest snapshot, or zero if the buffer did not run dry.
+    pub fn overshoot(&self) -> Duration {
+        self.overshoot
+    }
+
+    /// The number of buffered snapshots newer than the point being shown.
+    pub fn depth(&self) -> usize {
+        let Some((position, _)) = self.playhead else {
+            return self.snapshots.len();
+        };
+        let shown = self.snapshots.partition_point(|s| s.time <= position);
+        self.snapshots.len() - shown
+    }
+
+    /// The newest snapshot, and when it arrived.
+    pub fn newest(&self) -> Option<(&[Option<Player>], Instant)> {
+        let (tick, _, arrival) = self.newest?;
+        let newest = self.snapshots.iter().rfind(|s| s.tick == tick)?;
+        Some((&newest.players, arrival))
+    }
+
+    /// Advance playback to `now`, and return the players at that point.
+    ///
+    /// `sent` is the server time at which a snapshot arriving now would have been sent, if the clocks are synchronized.
+    /// Otherwise it is estimated from the arrival of the newest snapshot, which is affected by its jitter.
+    ///
+    /// Returns `None` if no snapshots have arrived yet.
+    pub fn sample(&mut self, now: Instant, sent: Option<f64>) -> Option<Vec<Option<Player>>> {
+        let (_, newest, arrival) = self.newest?;
+        let sent = sent.unwrap_or_else(|| newest + (now - arrival).as_secs_f64());
+        let target = sent - self.delay().as_secs_f64();
+
+        let position = match self.playhead {
+            Some((position, last)) if (target - position).abs() <= MAX_LAG * self.interval => {
+                let lag = (target - position) / self.interval;
+                let speed = 1. + lag.clamp(-MAX_DRIFT, MAX_DRIFT);
+                position + (now - last).as_secs_f64() * speed
+            }
+            _ => target,
+        };
+        let position = position.max(self.snapshots.front()?.time);
+        self.playhead = Some((position, now));
+
+        let overshoot = position - newest;
+        if overshoot > 0. && self.overshoot.is_zero() {
+            self.underruns += 1;
+        }
+        self.overshoot = Duration::from_secs_f64(overshoot.max(0.));
+
+        Some(self.players_at(position))
+    }
+
+    /// The players at a server time.
+    fn players_at(&self, time: f64) -> Vec<Option<Player>> {
+        let next = self.snapshots.partition_point(|s| s.time <= time);
+
+        let (from, to, t) = match (next.checked_sub(1), self.snapshots.get(next)) {
+            (Some(prev), Some(to)) => {
+                let from = &self.snapshots[prev];
+                let span = to.time - from.time;
+                if span <= 0. {
+                    return to.players.clone();
+                }
+                (from, to, (time - from.time) / span)
+            }
+            (Some(newest), None) => {
+                let to = &self.snapshots[newest];
+                match (self.underrun, newest.checked_sub(1)) {
+                    (Underrun::Linear, Some(prev)) if to.time > self.snapshots[prev].time => {
+                        let from = &self.snapshots[prev];
+                        let span = to.time - from.time;
+                        let past = (time - to.time).min(MAX_LINEAR * span);
+                        (from, to, 1. + past / span)
+                    }
+                    _ => return to.players.clone(),
+                }
+            }
+            (None, _) => return self.snapshots[0].players.clone(),
+        };
+
+        let mut players = from.players.clone();
+        for (player, target) in players.iter_mut().zip(&to.players) {
+            if let (Some(player), Some(target)) = (player, target) {
+                player.pos = player.pos + (target.pos - player.pos) * t;
+            }
+        }
+        players
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Vec2;
+
+    fn players(x: f64) -> Vec<Option<Player>> {
+        let mut game = crate::model::Game::new();
+        let idx = game.add_player();
+        game.players[idx].as_mut().unwrap().pos = Vec2 { x, y: 0. };
+        game.players
+    }
+
+    fn x(players: &[Option<Player>]) -> f64 {
+        players[0].as_ref().unwrap().pos.x
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Push snapshot `tick`, sent every 100 ms of server time.
+    fn push(buffer: &mut SnapshotBuffer, tick: u64, x: f64, now: Instant) {
+        buffer.push(tick, ms(tick * 100), players(x), now);
+    }
+
+    #[test]
+    fn interpolates_behind_the_newest_snapshot() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::new(Some(ms(100)), Underrun::Hold);
+        push(&mut buffer, 1, 0., start);
+        push(&mut buffer, 2, 10., start + ms(100));
+
+        assert_eq!(buffer.depth(), 2);
+
+        // halfway between the two snapshots, 100 ms behind the newest
+        let sampled = buffer.sample(start + ms(150), None).unwrap();
+        assert!((x(&sampled) - 5.).abs() < 1e-9);
+        assert_eq!(buffer.depth(), 1);
+    }
+
+    #[test]
+    fn synchronized_clock_replaces_the_arrival_estimate() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::new(Some(ms(100)), Underrun::Hold);
+        push(&mut buffer, 1, 0., start);
+        // arrived late, which would hold playback back without a synchronized clock
+        push(&mut buffer, 2, 10., start + ms(150));
+
+        let sampled = buffer.sample(start + ms(150), Some(0.25)).unwrap();
+        assert!((x(&sampled) - 5.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn out_of_order_snapshots_are_sorted_and_duplicates_ignored() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::new(None, Underrun::Hold);
+        push(&mut buffer, 3, 3., start);
+        push(&mut buffer, 1, 1., start);
+        push(&mut buffer, 3, 30., start);
+        push(&mut buffer, 2, 2., start);
+
+        let ticks: Vec<_> = buffer.snapshots.iter().map(|s| s.tick).collect();
+        assert_eq!(ticks, vec![1, 2, 3]);
+        assert_eq!(x(&buffer.snapshots[2].players), 3.);
+    }
+
+    #[test]
+    fn underrun_holds_or_continues_linearly() {
+        let start = Instant::now();
+        for (underrun, expected) in [(Underrun::Hold, 10.), (Underrun::Linear, 20.)] {
+            let mut buffer = SnapshotBuffer::new(Some(ms(0)), underrun);
+            push(&mut buffer, 1, 0., start);
+            push(&mut buffer, 2, 10., start + ms(100));
+
+            // far past the newest snapshot, linear movement is capped
+            let sampled = buffer.sample(start + ms(300), None).unwrap();
+            assert!((x(&sampled) - expected).abs() < 1e-9, "{underrun:?}");
+            assert_eq!(buffer.underruns(), 1);
+        }
+    }
+
+    #[test]
+    fn snapshots_with_the_same_time_are_not_divided_by() {
+        let start = Instant::now();
+        for underrun in [Underrun::Hold, Underrun::Linear] {
+            let mut buffer = SnapshotBuffer::new(Some(ms(0)), underrun);
+            buffer.push(1, ms(100), players(0.), start);
+            buffer.push(2, ms(100), players(10.), start);
+
+            assert_eq!(x(&buffer.players_at(0.1)), 10., "{underrun:?}");
+            assert_eq!(x(&buffer.players_at(0.2)), 10., "{underrun:?}");
+        }
+    }
+
+    #[test]
+    fn adaptive_delay_grows_with_jitter() {
+        let start = Instant::now();
+        let mut steady = SnapshotBuffer::new(None, Underrun::Hold);
+        let mut jittery = SnapshotBuffer::new(None, Underrun::Hold);
+        for i in 0..50 {
+            let jitter = if i % 2 == 0 { 0 } else { 40 };
+            let sent = ms(i * 50);
+            steady.push(i, sent, players(0.), start + sent);
+            jittery.push(i, sent, players(0.), start + sent + ms(jitter));
+        }
+
+        let steady = steady.delay().as_secs_f64();
+        assert!((steady - 0.05 - SAFETY_MARGIN).abs() < 1e-9, "{steady}");
+        assert!(jittery.delay().as_secs_f64() > steady + 0.03);
+    }
+
+    #[test]
+    fn playback_speed_is_limited_while_catching_up() {
+        let start = Instant::now();
+        let mut buffer = SnapshotBuffer::new(Some(ms(100)), Underrun::Hold);
+        for i in 0..20 {
+            let now = start + ms(i * 100);
+            push(&mut buffer, i, i as f64, now);
+            buffer.sample(now, None);
+        }
+
+        // a burst moves the target ahead, but playback only speeds up slightly
+        for i in 20..22 {
+            push(&mut buffer, i, i as f64, start + ms(1910));
+        }
+        let before = buffer.playhead.unwrap().0;
+        buffer.sample(start + ms(1920), None);
+        let advanced = buffer.playhead.unwrap().0 - before;
+
+        assert!(advanced > 0.02);
+        assert!(advanced <= 0.02 * (1. + MAX_DRIFT) + 1e-9);
+    }
+}
@@ -0,0 +1,428 @@
+//! Deterministic lockstep, where every peer simulates the game only from the inputs of all players.
+//!
+//! The server collects the inputs of each player, and confirms a frame once the input of every player for it
+//! has arrived. The clients simulate the confirmed frames, and wait whenever the next one has not arrived yet.
+//! Since every peer runs the same simulation from the same inputs, the states are only compared by checksums.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use crate::{client, model::*};
+
+/// Frames between checksums of the state.
+const CHECKSUM_INTERVAL: u64 = 30;
+
+/// Checksums kept for comparing with the ones of other peers.
+const CHECKSUMS_KEPT: usize = 16;
+
+/// Confirmed frames kept for clients which are behind. Clients further behind start over.
+const HISTORY_LEN: usize = 120;
+
+/// Most confirmed frames sent to a client in one packet.
+pub const MAX_FRAMES_PER_PACKET: usize = 32;
+
+/// Frames between sampling a local input and simulating it, before the round-trip time is measured.
+const DEFAULT_INPUT_DELAY: u64 = 6;
+
+/// Upper bound on the input delay, in frames.
+const MAX_INPUT_DELAY: u64 = 30;
+
+/// The inputs of every player slot for one frame. Empty slots have no player.
+pub type FrameInputs = Vec<Option<(i8, i8)>>;
+
+/// The frames between sampling a local input and simulating it, so that it can reach the other peers in time.
+///
+/// Covers the round trip through the server, plus a frame of margin.
+pub fn input_delay(rtt: Option<Duration>) -> u64 {
+    match rtt {
+        Some(rtt) => {
+            let frames = (rtt.as_secs_f64() / client::DELTA_TIME).ceil() as u64;
+            (frames + 1).min(MAX_INPUT_DELAY)
+        }
+        None => DEFAULT_INPUT_DELAY,
+    }
+}
+
+/// Counts of what the lockstep has done, for debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The number of times the next frame could not be simulated, because inputs were missing.
+    pub stalls: usize,
+    /// The number of checksums which differed from the ones of another peer.
+    pub desyncs: usize,
+    /// The current input delay, in frames.
+    pub input_delay: u64,
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Lockstep: input delay {} frames, {} stalls, {} desyncs",
+            self.input_delay, self.stalls, self.desyncs
+        )
+    }
+}
+
+/// A game simulated one frame at a time, once the inputs of all players for the frame are present.
+///
+/// The server adds the inputs of each player with `add_inputs`, and the frames are confirmed as they complete.
+/// Clients add the frames confirmed by the server with `add_frames`.
+pub struct Lockstep {
+    /// The next frame to simulate.
+    frame: u64,
+    /// The state before `frame`.
+    game: Game,
+    /// Confirmed inputs for `frame` and later, which arrived ahead of time.
+    pending: BTreeMap<u64, FrameInputs>,
+    /// The inputs of the most recent frames, oldest first, ending with the frame before `frame`.
+    history: VecDeque<FrameInputs>,
+    /// Which player slots take part, so that frames wait for their inputs. Only used on the server.
+    members: Vec<bool>,
+    /// The inputs received from each player, by frame, which have not been simulated yet.
+    inputs: Vec<BTreeMap<u64, (i8, i8)>>,
+    /// Recent checksums of the state, oldest first, along with their frames.
+    checksums: VecDeque<(u64, u64)>,
+    /// The newest frame whose checksum was compared with the one of another peer.
+    checked: u64,
+    stats: Stats,
+}
+
+impl Lockstep {
+    /// Start simulating from `game`, as the state before `frame`.
+    pub fn new(game: Game, frame: u64) -> Self {
+        Self {
+            frame,
+            game,
+            pending: BTreeMap::new(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            members: Vec::new(),
+            inputs: Vec::new(),
+            checksums: VecDeque::with_capacity(CHECKSUMS_KEPT),
+            checked: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// The next frame to simulate.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The state before the next frame.
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Let a player take part from the next frame on. The frames wait for their inputs from then on.
+    pub fn add_member(&mut self, player_idx: usize) {
+        if player_idx >= self.members.len() {
+            self.members.resize(player_idx + 1, false);
+        }
+        self.members[player_idx] = true;
+    }
+
+    /// Stop waiting for the inputs of a player, who leaves the game in the next frame.
+    pub fn remove_member(&mut self, player_idx: usize) {
+        if let Some(member) = self.members.get_mut(player_idx) {
+            *member = false;
+        }
+        if let Some(inputs) = self.inputs.get_mut(player_idx) {
+            inputs.clear();
+        }
+    }
+
+    /// Add the inputs of a player, newest first, where the first one is for `frame`.
+    ///
+    /// Inputs for frames which have already been simulated are ignored.
+    pub fn add_inputs(&mut self, player_idx: usize, frame: u64, inputs: &[(i8, i8)]) {
+        if player_idx >= self.inputs.len() {
+            self.inputs.resize_with(player_idx + 1, BTreeMap::new);
+        }
+        for (number, &input) in (self.frame..=frame).rev().zip(inputs) {
+            self.inputs[player_idx].entry(number).or_insert(input);
+        }
+    }
+
+    /// Add the local input for the frame after the newest one, unless that is more than `delay` frames ahead.
+    pub fn queue_input(&mut self, player_idx: usize, input: (i8, i8), delay: u64) {
+        self.stats.input_delay = delay;
+        let next = self
+            .inputs
+            .get(player_idx)
+            .and_then(|inputs| inputs.last_key_value())
+            .map_or(self.frame, |(&newest, _)| newest + 1)
+            .max(self.frame);
+        if next <= self.frame + delay {
+            self.add_inputs(player_idx, next, &[input]);
+        }
+    }
+
+    /// The inputs of a player which have not been simulated yet, newest first.
+    ///
+    /// Returns the frame of the newest input along with the inputs.
+    pub fn pending_inputs(&self, player_idx: usize) -> Option<(u64, Vec<(i8, i8)>)> {
+        let inputs = self.inputs.get(player_idx)?;
+        let (&newest, _) = inputs.last_key_value()?;
+        Some((newest, inputs.values().rev().copied().collect()))
+    }
+
+    /// Add confirmed inputs for consecutive frames, oldest first, where the first are for `frame`.
+    pub fn add_frames(&mut self, frame: u64, frames: Vec<FrameInputs>) {
+        for (number, inputs) in (frame..).zip(frames) {
+            if number >= self.frame {
+                self.pending.insert(number, inputs);
+            }
+        }
+    }
+
+    /// Simulate every frame whose inputs are all present, and return how many there were.
+    ///
+    /// A call which can not simulate any frame counts as a stall.
+    pub fn advance(&mut self) -> u64 {
+        let start = self.frame;
+        while let Some(inputs) = self.next_inputs() {
+            self.step(inputs);
+        }
+        if self.frame == start {
+            self.stats.stalls += 1;
+        }
+        self.frame - start
+    }
+
+    /// The confirmed inputs of up to `count` frames from `frame`, or `None` if they are no longer kept.
+    pub fn confirmed(&self, frame: u64, count: usize) -> Option<Vec<FrameInputs>> {
+        let oldest = self.frame - self.history.len() as u64;
+        let skipped = frame.checked_sub(oldest)? as usize;
+        Some(
+            self.history
+                .iter()
+                .skip(skipped)
+                .take(count)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// The newest checksum of the state, along with its frame.
+    pub fn newest_checksum(&self) -> Option<(u64, u64)> {
+        self.checksums.back().copied()
+    }
+
+    /// Whether the checksum of another peer matches the own one of the same frame,
+    /// or `None` if the own checksum of that frame is not known.
+    pub fn verify(&self, frame: u64, checksum: u64) -> Option<bool> {
+        let (_, own) = self.checksums.iter().find(|&&(f, _)| f == frame)?;
+        Some(*own == checksum)
+    }
+
+    /// Compare the checksum of another peer with the own one, counting a desync if they differ.
+    ///
+    /// Each frame is only compared once.
+    pub fn check(&mut self, frame: u64, checksum: u64) {
+        if frame <= self.checked {
+            return;
+        }
+        if let Some(same) = self.verify(frame, checksum) {
+            self.checked = frame;
+            if !same {
+                self.stats.desyncs += 1;
+            }
+        }
+    }
+
+    /// The inputs of the next frame, if they are all present.
+    fn next_inputs(&self) -> Option<FrameInputs> {
+        if let Some(inputs) = self.pending.get(&self.frame) {
+            return Some(inputs.clone());
+        }
+        if !self.members.contains(&true) {
+            return None;
+        }
+        self.members
+            .iter()
+            .enumerate()
+            .map(|(player_idx, &member)| match member {
+                true => self
+                    .inputs
+                    .get(player_idx)?
+                    .get(&self.frame)
+                    .map(|&i| Some(i)),
+                false => Some(None),
+            })
+            .collect()
+    }
+
+    /// Simulate one frame. Players join in the first frame with an input from them, and leave in the first without.
+    fn step(&mut self, inputs: FrameInputs) {
+        let players = &mut self.game.players;
+        if players.len() < inputs.len() {
+            players.resize_with(inputs.len(), || None);
+        }
+        for (player_idx, slot) in players.iter_mut().enumerate() {
+            match inputs.get(player_idx).copied().flatten() {
+                Some(_) => {
+                    slot.get_or_insert_with(Player::new);
+                }
+                None => *slot = None,
+            }
+        }
+        for (player_idx, input) in inputs.iter().enumerate() {
+            if let Some(input) = *input {
+                self.game
+                    .player_physics(player_idx, input, client::DELTA_TIME);
+            }
+        }
+
+        self.pending.remove(&self.frame);
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(inputs);
+        self.frame += 1;
+        for inputs in &mut self.inputs {
+            *inputs = inputs.split_off(&self.frame);
+        }
+
+        if self.frame.is_multiple_of(CHECKSUM_INTERVAL) {
+            if self.checksums.len() == CHECKSUMS_KEPT {
+                self.checksums.pop_front();
+            }
+            self.checksums
+                .push_back((self.frame, self.game.state_hash()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(members: usize) -> Lockstep {
+        let mut server = Lockstep::new(Game::new(), 0);
+        for player_idx in 0..members {
+            server.add_member(player_idx);
+        }
+        server
+    }
+
+    #[test]
+    fn frames_wait_for_every_member() {
+        let mut server = server(2);
+        server.add_inputs(0, 2, &[(1, 0), (1, 0), (1, 0)]);
+        assert_eq!(server.advance(), 0);
+        assert_eq!(server.stats().stalls, 1);
+
+        server.add_inputs(1, 1, &[(0, -1), (0, 0)]);
+        assert_eq!(server.advance(), 2);
+        assert_eq!(server.frame(), 2);
+        assert_eq!(server.game().players.iter().flatten().count(), 2);
+        assert_eq!(
+            server.confirmed(1, 8),
+            Some(vec![vec![Some((1, 0)), Some((0, -1))]])
+        );
+    }
+
+    #[test]
+    fn clients_simulating_confirmed_frames_agree() {
+        let mut server = server(2);
+        let mut client = Lockstep::new(Game::new(), 0);
+        for frame in 0..2 * CHECKSUM_INTERVAL {
+            let x = if frame % 20 < 10 { 1 } else { -1 };
+            server.add_inputs(0, frame, &[(x, 0)]);
+            server.add_inputs(1, frame, &[(-x, -1)]);
+        }
+        server.advance();
+
+        // the frames arrive out of order
+        let frames = server.confirmed(0, 100).unwrap();
+        let (first, second) = frames.split_at(40);
+        client.add_frames(40, second.to_vec());
+        assert_eq!(client.advance(), 0);
+        client.add_frames(0, first.to_vec());
+        assert_eq!(client.advance(), 2 * CHECKSUM_INTERVAL);
+
+        assert_eq!(client.game(), server.game());
+        let (frame, checksum) = server.newest_checksum().unwrap();
+        assert_eq!(frame, 2 * CHECKSUM_INTERVAL);
+        assert_eq!(client.verify(frame, checksum), Some(true));
+        client.check(frame, checksum);
+        assert_eq!(client.stats().desyncs, 0);
+
+        client.check(CHECKSUM_INTERVAL, 42);
+        assert_eq!(
+            client.stats().desyncs,
+            0,
+            "older frames were already compared"
+        );
+        let mut diverged = Lockstep::new(Game::new(), 0);
+        diverged.add_frames(1, frames[1..].to_vec());
+        diverged.pending.insert(0, vec![Some((0, 0)), Some((0, 0))]);
+        diverged.advance();
+        diverged.check(frame, checksum);
+        assert_eq!(diverged.stats().desyncs, 1);
+    }
+
+    #[test]
+    fn players_join_and_leave_with_the_frames() {
+        let mut server = server(1);
+        server.add_inputs(0, 0, &[(0, 0)]);
+        server.advance();
+
+        server.add_member(1);
+        server.add_inputs(0, 1, &[(0, 0)]);
+        assert_eq!(server.advance(), 0, "waits for the new player");
+        server.add_inputs(1, 1, &[(1, 0)]);
+        server.advance();
+        assert!(server.game().players[1].is_some());
+
+        server.remove_member(0);
+        server.add_inputs(1, 2, &[(1, 0)]);
+        server.advance();
+        assert!(server.game().players[0].is_none());
+        assert_eq!(server.confirmed(2, 1), Some(vec![vec![None, Some((1, 0))]]));
+    }
+
+    #[test]
+    fn local_inputs_are_queued_up_to_the_delay() {
+        let mut client = Lockstep::new(Game::new(), 10);
+        for x in 0..6 {
+            client.queue_input(0, (x, 0), 3);
+        }
+        assert_eq!(
+            client.pending_inputs(0),
+            Some((13, vec![(3, 0), (2, 0), (1, 0), (0, 0)]))
+        );
+
+        client.add_frames(10, vec![vec![Some((0, 0))]]);
+        client.advance();
+        assert_eq!(client.pending_inputs(0).map(|(_, i)| i.len()), Some(3));
+        client.queue_input(0, (-1, 0), 3);
+        assert_eq!(client.pending_inputs(0).map(|(frame, _)| frame), Some(14));
+    }
+
+    #[test]
+    fn old_frames_are_not_kept() {
+        let mut server = server(1);
+        for frame in 0..HISTORY_LEN as u64 + 10 {
+            server.add_inputs(0, frame, &[(0, 0)]);
+        }
+        server.advance();
+        assert_eq!(server.confirmed(5, 1), None);
+        assert_eq!(server.confirmed(10, 1).map(|f| f.len()), Some(1));
+        assert_eq!(server.confirmed(server.frame(), 1), Some(Vec::new()));
+    }
+
+    #[test]
+    fn input_delay_covers_the_round_trip() {
+        assert_eq!(input_delay(None), DEFAULT_INPUT_DELAY);
+        assert_eq!(input_delay(Some(Duration::from_millis(90))), 7);
+        assert_eq!(input_delay(Some(Duration::from_secs(5))), MAX_INPUT_DELAY);
+    }
+}
@@ -7,30 +7,54 @@
 
 use std::error::Error;
 
+use config::Command;
+
+mod bot;
 mod client;
+mod clock;
+mod config;
+mod input_buffer;
+mod interpolation;
+mod lockstep;
 mod math;
 mod model;
+mod net_graph;
 mod netcode;
 mod networking;
 mod render;
+mod rollback;
 mod server;
+mod snapshot;
 mod sys;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = std::env::args();
+    let (command, config) = config::parse(std::env::args().skip(1))?;
+    let codec = networking::codec(&config.codec).ok_or("unknown codec")?;
+
+    let shared_state = model::Game::new();
 
-    let mode = args.nth(1).unwrap_or_default();
+    match command {
+        Command::Help => {
+            println!("{}", config::USAGE);
+            return Ok(());
+        }
+        // bots never open a window
+        Command::Bot => return bot::run(&config, shared_state),
+        Command::Server if config.server.headless => {
+            return server::run(None, &*codec, shared_state, &config);
+        }
+        _ => (),
+    }
 
     let sdl = sdl2::init()?;
     let video = sdl.video()?;
-    let ctx = sys::init_sdl_systems(&sdl, &video)?;
+    let ctx = sys::init_sdl_systems(&sdl, &video, config.scale)?;
     let ttf = sdl2::ttf::init()?;
     let font = sys::load_font(&ttf)?;
 
-    let shared_state = model::Game::new();
-
-    match &mode[..] {
-        "server" | "--server" => server::run(ctx, &font, shared_state),
-        _ => client::run(ctx, &font, shared_state),
+    if command == Command::Server {
+        server::run(Some((ctx, &font)), &*codec, shared_state, &config)
+    } else {
+        client::run(ctx, &font, &*codec, shared_state, &config)
     }
 }
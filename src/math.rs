@@ -1,6 +1,6 @@
 //! Mathematical types and utility.
 
-use std::ops::{Add, AddAssign, Mul, Sub};
+use std::ops::{Add, AddAssign, Mul, Neg, Sub};
 
 /// A 2-dimensional vector, containing x and y components.
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
@@ -80,6 +80,181 @@ impl Mul for Vec2 {
     }
 }
 
+/// A fixed-point number with 32 fractional bits, whose arithmetic gives the same result on every platform.
+///
+/// Floating point results can differ in the last bits between builds and platforms,
+/// for example with fused multiply-add or a different `powi`. Fixed-point arithmetic only uses integers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    const FRAC_BITS: u32 = 32;
+
+    pub const ZERO: Self = Fixed(0);
+    pub const ONE: Self = Fixed(1 << Self::FRAC_BITS);
+    pub const HALF: Self = Fixed(1 << (Self::FRAC_BITS - 1));
+
+    pub const fn from_int(v: i64) -> Self {
+        Fixed(v << Self::FRAC_BITS)
+    }
+
+    /// The nearest fixed-point number.
+    ///
+    /// Exact for numbers converted from fixed point, so that states stored as floats are not changed.
+    pub fn from_f64(v: f64) -> Self {
+        Fixed((v * Self::ONE.0 as f64).round() as i64)
+    }
+
+    /// The value as a float, which is exact within the range of the game.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / Self::ONE.0 as f64
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.abs())
+    }
+
+    /// 1 if the number is positive or zero, and -1 if it is negative, like `f64::signum` for positive zero.
+    pub fn signum(self) -> Self {
+        if self.0 < 0 { -Self::ONE } else { Self::ONE }
+    }
+}
+
+impl Add for Fixed {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Fixed(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Fixed(self.0 - rhs.0)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Fixed(-self.0)
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Self;
+    /// Multiplies with full precision, rounding towards negative infinity.
+    fn mul(self, rhs: Self) -> Self::Output {
+        Fixed(((self.0 as i128 * rhs.0 as i128) >> Self::FRAC_BITS) as i64)
+    }
+}
+
+/// A 2-dimensional vector of fixed-point numbers, for physics which have to be deterministic.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FixedVec2 {
+    pub x: Fixed,
+    pub y: Fixed,
+}
+
+impl FixedVec2 {
+    pub const fn new(x: Fixed, y: Fixed) -> Self {
+        FixedVec2 { x, y }
+    }
+
+    /// The squared distance to `rhs`, which orders distances the same way without a square root.
+    pub fn dist_squared(self, rhs: FixedVec2) -> Fixed {
+        let d = self - rhs;
+        d.x * d.x + d.y * d.y
+    }
+}
+
+impl From<Vec2> for FixedVec2 {
+    fn from(v: Vec2) -> Self {
+        FixedVec2::new(Fixed::from_f64(v.x), Fixed::from_f64(v.y))
+    }
+}
+
+impl From<FixedVec2> for Vec2 {
+    fn from(v: FixedVec2) -> Self {
+        Vec2::new(v.x.to_f64(), v.y.to_f64())
+    }
+}
+
+impl Add for FixedVec2 {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        FixedVec2::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+impl Sub for FixedVec2 {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        FixedVec2::new(self.x - rhs.x, self.y - rhs.y)
+    }
+}
+
+impl AddAssign for FixedVec2 {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl Mul<Fixed> for FixedVec2 {
+    type Output = Self;
+    fn mul(self, rhs: Fixed) -> Self::Output {
+        FixedVec2::new(self.x * rhs, self.y * rhs)
+    }
+}
+
+/// A small and fast pseudo-random number generator (xorshift64*).
+///
+/// Good enough for simulating networks and bots, but not for anything security related.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Create a generator from a seed. The same seed always gives the same numbers.
+    pub fn new(seed: u64) -> Self {
+        // the state must never be zero
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15 | 1)
+    }
+
+    /// Create a generator seeded from the system clock.
+    pub fn from_time() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        Rng::new(nanos as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A uniformly distributed number in `[0, 1)`.
+    pub fn f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns `true` with the given probability, between 0 and 1.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        self.f64() < probability
+    }
+
+    /// A normally distributed number with mean 0 and standard deviation 1.
+    pub fn normal(&mut self) -> f64 {
+        // Box-Muller transform
+        let u = 1. - self.f64();
+        let v = self.f64();
+        (-2. * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -152,4 +327,61 @@ mod tests {
         let result = v1 * v2;
         assert_eq!(result, 11.0);
     }
+
+    #[test]
+    fn fixed_round_trip() {
+        for v in [0., 1.5, -2.25, 160.123, -0.000_01] {
+            let fixed = Fixed::from_f64(v);
+            assert!((fixed.to_f64() - v).abs() < 1e-9);
+            assert_eq!(Fixed::from_f64(fixed.to_f64()), fixed);
+        }
+        assert_eq!(Fixed::from_int(-3).to_f64(), -3.);
+    }
+
+    #[test]
+    fn fixed_arithmetic() {
+        let a = Fixed::from_f64(1.5);
+        let b = Fixed::from_f64(-2.25);
+        assert_eq!((a + b).to_f64(), -0.75);
+        assert_eq!((a - b).to_f64(), 3.75);
+        assert_eq!((a * b).to_f64(), -3.375);
+        assert_eq!(Fixed::HALF * Fixed::from_int(3), Fixed::from_f64(1.5));
+        assert_eq!(b.abs(), Fixed::from_f64(2.25));
+        assert_eq!(b.signum(), -Fixed::ONE);
+        assert_eq!(Fixed::ZERO.signum(), Fixed::ONE);
+
+        // the smallest step times a half rounds down
+        let tiny = Fixed(1);
+        assert_eq!(tiny * Fixed::HALF, Fixed::ZERO);
+        assert_eq!(-tiny * Fixed::HALF, -tiny);
+    }
+
+    #[test]
+    fn fixed_vectors() {
+        let v = FixedVec2::from(Vec2::new(1.0, 2.0));
+        let w = FixedVec2::from(Vec2::new(4.0, 6.0));
+        assert_eq!(v.dist_squared(w), Fixed::from_int(25));
+        assert_eq!(Vec2::from(w - v), Vec2::new(3.0, 4.0));
+        assert_eq!(Vec2::from(v * Fixed::from_int(2)), Vec2::new(2.0, 4.0));
+    }
+
+    #[test]
+    fn rng_is_deterministic() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn rng_f64_range() {
+        let mut rng = Rng::new(0);
+        for _ in 0..1000 {
+            let x = rng.f64();
+            assert!((0. ..1.).contains(&x));
+        }
+        assert!(!rng.chance(0.));
+        assert!(rng.chance(1.));
+    }
 }
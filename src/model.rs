@@ -1,6 +1,6 @@
 //! Items implementing game logic and communication between server and client.
 
-use crate::math::Vec2;
+use crate::math::{Fixed, FixedVec2, Vec2};
 
 const LOGICAL_WIDTH: u32 = 320;
 const LOGICAL_HEIGHT: u32 = 240;
@@ -17,9 +17,92 @@ const GRAVITY: Vec2 = Vec2 {
     y: 9.81 * 20.,
 };
 
+/// The version of the protocol spoken by this build.
+///
+/// Must be increased whenever the packets change, so that old clients are rejected by the server.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// The default number of player slots on a server.
+pub const MAX_PLAYERS: usize = 6;
+
+/// How the game is simulated and kept in sync, chosen by the server.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// The server simulates the game from the clients' inputs, and sends snapshots to the clients.
+    /// Clients can use prediction, reconciliation, interpolation and extrapolation.
+    #[default]
+    Authoritative,
+    /// Every peer simulates all players from inputs relayed by the server,
+    /// predicting inputs which have not arrived and rolling back when they do.
+    /// The server sends its final states, which the peers correct themselves with.
+    Rollback,
+    /// Every peer simulates all players from the inputs relayed by the server,
+    /// and only advances once the inputs of every player for the next frame are present.
+    /// The peers compare checksums of their states to detect desyncs.
+    Lockstep,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "authoritative" => Ok(Mode::Authoritative),
+            "rollback" => Ok(Mode::Rollback),
+            "lockstep" => Ok(Mode::Lockstep),
+            _ => Err(format!("unknown mode: {s}")),
+        }
+    }
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Authoritative => write!(f, "authoritative"),
+            Mode::Rollback => write!(f, "rollback"),
+            Mode::Lockstep => write!(f, "lockstep"),
+        }
+    }
+}
+
+/// Which arithmetic the physics use, chosen by the server.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Physics {
+    /// Floating point, whose results can differ in the last bits between builds and platforms.
+    #[default]
+    Float,
+    /// Fixed point, whose results are the same bit for bit everywhere,
+    /// so that peers simulating the same inputs stay in sync.
+    Fixed,
+}
+
+impl std::str::FromStr for Physics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "float" => Ok(Physics::Float),
+            "fixed" => Ok(Physics::Fixed),
+            _ => Err(format!("unknown physics: {s}")),
+        }
+    }
+}
+
+impl std::fmt::Display for Physics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Physics::Float => write!(f, "float"),
+            Physics::Fixed => write!(f, "fixed"),
+        }
+    }
+}
+
 /// One movement input.
 ///
 /// Contains an id/sequence number, which is used to implement reconciliation.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Movement {
     /// The sequence number.
     pub id: usize,
@@ -30,7 +113,7 @@ pub struct Movement {
 /// A message DTO, sent from the client to the server.
 ///
 /// Contains information about the client's inputs.
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
 pub struct Message {
     /// The sequence number.
     pub id: usize,
@@ -38,26 +121,267 @@ pub struct Message {
     pub x: i8,
     /// The y value of the directionality of the movement input.
     pub y: i8,
+    /// Earlier movements which the server has not acknowledged yet, newest first,
+    /// so that a lost message does not lose its movement.
+    /// The first one has sequence number `id - 1`, the next `id - 2` and so on.
+    pub redundant: Vec<(i8, i8)>,
+    /// The sequence number of the last snapshot received by the client.
+    /// The server uses it as the baseline for delta compression.
+    pub snapshot_ack: usize,
+}
+
+/// A packet sent from the client to the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub enum ClientPacket {
+    /// A request to join the game. Answered with `ServerPacket::Accept` or `ServerPacket::Reject`.
+    Connect {
+        /// The `PROTOCOL_VERSION` of the client.
+        protocol_version: u32,
+    },
+    /// One movement input.
+    Input(Message),
+    /// The client is leaving the game, and its player should be removed.
+    Disconnect,
+    /// The client is missing the level, or has the wrong one, and wants a new `ServerPacket::Welcome`.
+    LevelRequest,
+    /// The newest inputs of the client in `Mode::Rollback`, to be relayed to the other clients.
+    PeerInput {
+        /// The frame of the first input.
+        frame: u64,
+        /// The inputs, newest first, for `frame`, `frame - 1` and so on.
+        inputs: Vec<(i8, i8)>,
+    },
+    /// The newest inputs of the client in `Mode::Lockstep`, and how far it has come.
+    LockstepInput {
+        /// The frame of the first input.
+        frame: u64,
+        /// The inputs, newest first, for `frame`, `frame - 1` and so on.
+        inputs: Vec<(i8, i8)>,
+        /// The next frame the client needs the inputs of all players for.
+        next_frame: u64,
+        /// The newest checksum of the client's state, along with its frame.
+        checksum: Option<(u64, u64)>,
+    },
+    /// A request for the server's time, answered with `ServerPacket::Pong`.
+    Ping {
+        /// The client's time when the ping was sent, in microseconds.
+        client_time_us: u64,
+    },
+}
+
+/// A packet sent from the server to the client.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub enum ServerPacket {
+    /// The client has joined the game.
+    Accept {
+        /// The player ID assigned to the client.
+        player_idx: usize,
+        /// The number of player slots on the server.
+        max_players: usize,
+        /// How the game is simulated.
+        mode: Mode,
+    },
+    /// The client is not allowed to join the game.
+    Reject(RejectReason),
+    /// The level layout, sent when the client connects and whenever the client requests it.
+    Welcome {
+        /// All platforms composing the level layout.
+        platforms: Vec<Platform>,
+        /// The hash of the level, as given by `Game::level_hash`.
+        level_hash: u64,
+        /// Which arithmetic the physics use.
+        physics: Physics,
+    },
+    /// A snapshot of the game, sent each tick.
+    Snapshot(ServerResponse),
+    /// The answer to a `ClientPacket::Ping`, used for clock synchronization.
+    Pong {
+        /// The client's time from the ping.
+        client_time_us: u64,
+        /// The server's time when the ping was answered, in microseconds, on the same clock as snapshots.
+        server_time_us: u64,
+    },
+    /// The inputs of another client in `Mode::Rollback`, relayed by the server.
+    PeerInput {
+        /// The player ID of the other client.
+        player_idx: usize,
+        /// The frame of the first input.
+        frame: u64,
+        /// The inputs, newest first, for `frame`, `frame - 1` and so on.
+        inputs: Vec<(i8, i8)>,
+    },
+    /// The exact state a client in `Mode::Lockstep` starts simulating from,
+    /// since the state in snapshots is quantized.
+    LockstepStart {
+        /// The next frame to simulate.
+        frame: u64,
+        /// The players before `frame`.
+        players: Vec<Option<Player>>,
+    },
+    /// The inputs of all players for consecutive frames in `Mode::Lockstep`, once all of them are present.
+    LockstepInputs {
+        /// The frame of the first inputs.
+        frame: u64,
+        /// The inputs of each player slot for each frame, oldest first. Empty slots have no player.
+        inputs: Vec<Vec<Option<(i8, i8)>>>,
+        /// The newest checksum of the server's state, along with its frame.
+        checksum: Option<(u64, u64)>,
+    },
+}
+
+/// The reason a client was not allowed to join the game.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub enum RejectReason {
+    /// The client and server speak different versions of the protocol.
+    VersionMismatch {
+        /// The `PROTOCOL_VERSION` of the server.
+        server_version: u32,
+    },
+    /// All player slots are taken.
+    ServerFull {
+        /// The number of player slots on the server.
+        max_players: usize,
+    },
+}
+
+impl Message {
+    /// All movements in the message, oldest first.
+    pub fn movements(&self) -> impl Iterator<Item = Movement> {
+        let newest = Movement {
+            id: self.id,
+            dir: (self.x, self.y),
+        };
+        let earlier = self
+            .redundant
+            .iter()
+            .zip(1..self.id)
+            .rev()
+            .map(|(&dir, back)| Movement {
+                id: self.id - back,
+                dir,
+            });
+        earlier.chain([newest])
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::VersionMismatch { server_version } => write!(
+                f,
+                "server uses protocol version {server_version}, client uses {PROTOCOL_VERSION}"
+            ),
+            RejectReason::ServerFull { max_players } => {
+                write!(f, "server is full ({max_players} players)")
+            }
+        }
+    }
 }
 
+impl std::error::Error for RejectReason {}
+
 /// A server response DTO, sent from the server to the client each tick.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
 pub struct ServerResponse {
     /// The last client message that was acknowledged before the server sent this response.
     pub ack_id: usize,
-    /// The player ID of the client receiving the message.
+    /// The `Player::state_hash` of the client's player after simulating the acknowledged message,
+    /// which the client compares with its prediction. Only sent in `Mode::Authoritative`, once a message was simulated.
+    pub player_hash: Option<u64>,
+    /// The hash of the server's level, used by the client to detect a missing or outdated level.
+    pub level_hash: u64,
+    /// The sequence number of the snapshot. Starts at 1 and increases with each broadcast.
+    pub sequence: usize,
+    /// The number of simulation ticks the server had run when the snapshot was taken. Never decreases.
+    pub tick: u64,
+    /// Time since the server started, in microseconds, when the snapshot was taken.
+    pub time_us: u64,
+    /// The state of the server's buffer of inputs from this client.
+    pub input: InputStatus,
+    /// The current player states on the server, as of this response being sent.
+    pub snapshot: Snapshot,
+}
+
+/// The state of the server's buffer of inputs from one client.
+///
+/// The client adjusts how often it sends inputs to keep the depth at the target.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputStatus {
+    /// The number of inputs waiting to be simulated.
+    pub depth: usize,
+    /// The depth the server aims for, to absorb jitter in the arrival of inputs.
+    pub target: usize,
+    /// The number of times the buffer ran dry, so that the player was not simulated.
+    pub underruns: usize,
+    /// The number of inputs dropped because the buffer was full.
+    pub overruns: usize,
+}
+
+impl std::fmt::Display for InputStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Input buffer: {}/{}, {} underruns, {} overruns",
+            self.depth, self.target, self.underruns, self.overruns
+        )
+    }
+}
+
+/// The player states in a `ServerResponse`, either in full or relative to an earlier snapshot.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub enum Snapshot {
+    /// All player slots.
+    Full(Vec<Option<Player>>),
+    /// Only the changes since the snapshot with the sequence number `baseline`.
+    Delta {
+        /// The sequence number of the snapshot the changes are relative to.
+        baseline: usize,
+        /// The number of player slots.
+        len: usize,
+        /// The changed player slots. Slots not listed are unchanged.
+        changes: Vec<PlayerDelta>,
+    },
+}
+
+/// The change to one player slot between two snapshots.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct PlayerDelta {
+    /// The player ID.
     pub player_idx: usize,
-    /// The current game state on the server, as of this response being sent.
-    pub game: Game,
+    /// The new state of the slot.
+    pub change: PlayerChange,
+}
+
+/// The change to one player.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub enum PlayerChange {
+    /// The player left the game.
+    Removed,
+    /// The fields that changed. A new player has all fields set.
+    Changed {
+        /// The new position, if it changed.
+        pos: Option<Vec2>,
+        /// The new velocity, if it changed.
+        velocity: Option<Vec2>,
+        /// The new size, if it changed.
+        size: Option<f64>,
+        /// The new airtime state, if it changed.
+        state: Option<PlayerState>,
+    },
 }
 
 /// A game state. Includes the level layout and the current player data.
-#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
 pub struct Game {
     /// All platforms composing the level layout.
     pub platforms: Vec<Platform>,
     /// The states of the players. An player ID is an index into this vector.
-    pub players: Vec<Player>,
+    ///
+    /// Slots of disconnected players are left empty, so that the IDs of the remaining players stay the same.
+    pub players: Vec<Option<Player>>,
+    /// Which arithmetic the physics use.
+    #[serde(default)]
+    pub physics: Physics,
 }
 
 impl Game {
@@ -87,55 +411,166 @@ impl Game {
                     pos: Vec2::new(WIDTH, HALF_HEIGHT),
                 },
             ],
+            physics: Physics::Float,
+        }
+    }
+
+    /// A stable hash of the level layout.
+    ///
+    /// Computed with FNV-1a over the bits of the platform dimensions, so that it is the same for every build.
+    pub fn level_hash(&self) -> u64 {
+        let values = self
+            .platforms
+            .iter()
+            .flat_map(|p| [p.size.0, p.size.1, p.pos.x, p.pos.y]);
+        fnv1a(values.flat_map(|v| v.to_bits().to_le_bytes()))
+    }
+
+    /// A stable hash of the players, used as a checksum to detect when peers have diverged.
+    ///
+    /// Covers the exact bits of every field and which slots are empty, so it only matches identical states.
+    pub fn state_hash(&self) -> u64 {
+        let bytes = self.players.iter().flat_map(|player| match player {
+            Some(player) => [vec![1], player.hash_bytes()].concat(),
+            None => vec![0],
+        });
+        fnv1a(bytes)
+    }
+
+    /// Add a player in the first free slot, and return its player ID.
+    pub fn add_player(&mut self) -> usize {
+        match self.players.iter().position(Option::is_none) {
+            Some(i) => {
+                self.players[i] = Some(Player::new());
+                i
+            }
+            None => {
+                self.players.push(Some(Player::new()));
+                self.players.len() - 1
+            }
+        }
+    }
+
+    /// Remove a player, leaving its slot empty.
+    pub fn remove_player(&mut self, player_idx: usize) {
+        if let Some(slot) = self.players.get_mut(player_idx) {
+            *slot = None;
         }
     }
 
     /// Apply physics calculations to players, using the time delta specified.
     /// Physics are applied per new movement, even if that movement is (0, 0).
+    ///
+    /// Does nothing if there is no player with the given ID.
     pub fn player_physics(&mut self, player_idx: usize, movement: (i8, i8), dt: f64) {
-        let player = &mut self.players[player_idx];
-        let current_velocity = player.velocity.x;
-        let target_velocity = movement.0 as f64 * PLAYER_TOP_SPEED;
-        let velocity_diff = target_velocity - current_velocity;
+        let Some(Some(player)) = self.players.get_mut(player_idx) else {
+            return;
+        };
+        match self.physics {
+            Physics::Float => float_physics(player, &self.platforms, movement, dt),
+            Physics::Fixed => fixed_physics(player, &self.platforms, movement, dt),
+        }
+    }
+}
 
-        let acc = match player.state {
+/// The physics of one player in floating point.
+fn float_physics(player: &mut Player, platforms: &[Platform], movement: (i8, i8), dt: f64) {
+    let current_velocity = player.velocity.x;
+    let target_velocity = movement.0 as f64 * PLAYER_TOP_SPEED;
+    let velocity_diff = target_velocity - current_velocity;
+
+    let acc = match player.state {
+        PlayerState::Grounded => {
+            let direction = if velocity_diff < 0. { -1. } else { 1. };
+            PLAYER_ACCELERATION * direction * dt
+        }
+        _ => PLAYER_ACCELERATION * movement.0 as f64 * dt,
+    };
+
+    let delta_v = if acc.abs() < velocity_diff.abs() {
+        (acc * velocity_diff).signum() * acc
+    } else {
+        velocity_diff
+    };
+
+    player.velocity.x = current_velocity + delta_v;
+
+    if movement.1 == -1 {
+        match player.state {
             PlayerState::Grounded => {
-                let direction = if velocity_diff < 0. { -1. } else { 1. };
-                PLAYER_ACCELERATION * direction * dt
+                player.velocity.y -= JUMP_SPEED;
             }
-            _ => PLAYER_ACCELERATION * movement.0 as f64 * dt,
-        };
+            PlayerState::WallBound(direction) => {
+                player.velocity.y = -JUMP_SPEED;
+                player.velocity.x -= direction as f64 * JUMP_SPEED * 1.5;
+            }
+            PlayerState::Airborne => (),
+        }
+    }
 
-        let delta_v = if acc.abs() < velocity_diff.abs() {
-            (acc * velocity_diff).signum() * acc
-        } else {
-            velocity_diff
-        };
+    player.velocity += GRAVITY * dt;
+    player.pos += player.velocity * dt - GRAVITY * dt.powi(2) * 0.5;
 
-        player.velocity.x = current_velocity + delta_v;
+    collide(player, platforms);
+}
 
-        if movement.1 == -1 {
-            match player.state {
-                PlayerState::Grounded => {
-                    player.velocity.y -= JUMP_SPEED;
-                }
-                PlayerState::WallBound(direction) => {
-                    player.velocity.y = -JUMP_SPEED;
-                    player.velocity.x -= direction as f64 * JUMP_SPEED * 1.5;
-                }
-                PlayerState::Airborne => (),
+/// The physics of one player in fixed point, following the same rules as `float_physics`.
+///
+/// The player is converted to fixed point and back, which is exact for states computed by these physics,
+/// so a player only ever holds values that the fixed-point arithmetic can represent.
+fn fixed_physics(player: &mut Player, platforms: &[Platform], movement: (i8, i8), dt: f64) {
+    let dt = Fixed::from_f64(dt);
+    let top_speed = Fixed::from_f64(PLAYER_TOP_SPEED);
+    let acceleration = Fixed::from_f64(PLAYER_ACCELERATION);
+    let jump_speed = Fixed::from_f64(JUMP_SPEED);
+    let gravity = FixedVec2::from(GRAVITY);
+    let x = Fixed::from_int(movement.0 as i64);
+
+    let mut body = FixedBody::from(&*player);
+
+    let current_velocity = body.velocity.x;
+    let velocity_diff = x * top_speed - current_velocity;
+
+    let acc = match body.state {
+        PlayerState::Grounded => velocity_diff.signum() * acceleration * dt,
+        _ => acceleration * x * dt,
+    };
+
+    let delta_v = if acc.abs() < velocity_diff.abs() {
+        (acc * velocity_diff).signum() * acc
+    } else {
+        velocity_diff
+    };
+
+    body.velocity.x = current_velocity + delta_v;
+
+    if movement.1 == -1 {
+        match body.state {
+            PlayerState::Grounded => {
+                body.velocity.y = body.velocity.y - jump_speed;
+            }
+            PlayerState::WallBound(direction) => {
+                body.velocity.y = -jump_speed;
+                body.velocity.x = body.velocity.x
+                    - Fixed::from_int(direction as i64) * jump_speed * Fixed::from_f64(1.5);
             }
+            PlayerState::Airborne => (),
         }
+    }
 
-        player.velocity += GRAVITY * dt;
-        player.pos += player.velocity * dt - GRAVITY * dt.powi(2) * 0.5;
+    body.velocity += gravity * dt;
+    body.pos += body.velocity * dt - gravity * (dt * dt) * Fixed::HALF;
 
-        collide(player, &self.platforms);
-    }
+    let platforms: Vec<_> = platforms.iter().map(FixedPlatform::from).collect();
+    body.collide(&platforms);
+
+    player.pos = body.pos.into();
+    player.velocity = body.velocity.into();
+    player.state = body.state;
 }
 
 /// A rectangle-shaped platform, which has collision with players.
-#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Platform {
     /// The (x, y) width and height.
     pub size: (f64, f64),
@@ -150,8 +585,106 @@ impl Platform {
     }
 }
 
+/// A platform in fixed point.
+struct FixedPlatform {
+    pos: FixedVec2,
+    half_size: FixedVec2,
+}
+
+impl From<&Platform> for FixedPlatform {
+    fn from(platform: &Platform) -> Self {
+        Self {
+            pos: platform.pos.into(),
+            half_size: FixedVec2::new(
+                Fixed::from_f64(platform.size.0) * Fixed::HALF,
+                Fixed::from_f64(platform.size.1) * Fixed::HALF,
+            ),
+        }
+    }
+}
+
+/// A player in fixed point, with the fields the physics change.
+struct FixedBody {
+    pos: FixedVec2,
+    velocity: FixedVec2,
+    radius: Fixed,
+    state: PlayerState,
+}
+
+impl From<&Player> for FixedBody {
+    fn from(player: &Player) -> Self {
+        Self {
+            pos: player.pos.into(),
+            velocity: player.velocity.into(),
+            radius: Fixed::from_f64(player.size) * Fixed::HALF,
+            state: player.state.clone(),
+        }
+    }
+}
+
+impl FixedBody {
+    /// Push the body out of the platforms, like `collide`.
+    fn collide(&mut self, platforms: &[FixedPlatform]) {
+        let mut collided = true;
+        self.state = PlayerState::Airborne;
+        while collided {
+            collided = false;
+            for platform in platforms {
+                let rad = FixedVec2::new(self.radius, self.radius);
+                let (topleft, botright) = (self.pos - rad, self.pos + rad);
+                let (platform_topleft, platform_botright) = (
+                    platform.pos - platform.half_size,
+                    platform.pos + platform.half_size,
+                );
+
+                if platform_botright.x > topleft.x
+                    && platform_topleft.x < botright.x
+                    && platform_botright.y > topleft.y
+                    && platform_topleft.y < botright.y
+                {
+                    collided = true;
+                    self.fix_position(platform);
+                }
+            }
+        }
+    }
+
+    /// Push the body out of one platform, like `fix_position`.
+    ///
+    /// Compares squared distances, which avoids the square root and orders the positions the same way.
+    fn fix_position(&mut self, platform: &FixedPlatform) {
+        let relative_position = self.pos - platform.pos;
+
+        let x_corrected = FixedVec2::new(
+            platform.pos.x + relative_position.x.signum() * (platform.half_size.x + self.radius),
+            platform.pos.y + relative_position.y,
+        );
+        let y_corrected = FixedVec2::new(
+            platform.pos.x + relative_position.x,
+            platform.pos.y + relative_position.y.signum() * (platform.half_size.y + self.radius),
+        );
+
+        self.pos = if self.pos.dist_squared(y_corrected) < self.pos.dist_squared(x_corrected) {
+            if relative_position.y < Fixed::ZERO {
+                self.state = PlayerState::Grounded
+            }
+            self.velocity.y = Fixed::ZERO;
+            y_corrected
+        } else {
+            let wall_direction = if relative_position.x < Fixed::ZERO {
+                1
+            } else {
+                -1
+            };
+            self.state = PlayerState::WallBound(wall_direction);
+            self.velocity.x = Fixed::ZERO;
+            x_corrected
+        };
+    }
+}
+
 /// A player's current state, including position and velocity.
-#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
 pub struct Player {
     /// The current position (center) of the player.
     pub pos: Vec2,
@@ -179,6 +712,40 @@ impl Player {
         }
     }
 
+    /// A stable hash of the player, used to detect when a prediction differs from the server.
+    ///
+    /// Covers the exact bits of every field, so any difference changes it, however small.
+    pub fn state_hash(&self) -> u64 {
+        fnv1a(self.hash_bytes())
+    }
+
+    /// Whether the position and velocity of the players differ by at most `tolerance`.
+    pub fn close_to(&self, other: &Player, tolerance: f64) -> bool {
+        self.pos.dist(other.pos) <= tolerance && self.velocity.dist(other.velocity) <= tolerance
+    }
+
+    /// The bytes hashed by `state_hash`, which are the same for every build and platform.
+    fn hash_bytes(&self) -> Vec<u8> {
+        let state = match self.state {
+            PlayerState::WallBound(direction) => [1, direction as u8],
+            PlayerState::Grounded => [2, 0],
+            PlayerState::Airborne => [3, 0],
+        };
+        let values = [
+            self.pos.x,
+            self.pos.y,
+            self.velocity.x,
+            self.velocity.y,
+            self.size,
+        ];
+        let mut bytes: Vec<_> = values
+            .iter()
+            .flat_map(|v| v.to_bits().to_le_bytes())
+            .collect();
+        bytes.extend(state);
+        bytes
+    }
+
     fn radius(&self) -> f64 {
         self.size * 0.50
     }
@@ -191,7 +758,7 @@ impl Player {
 }
 
 /// A type used to tell the physics what effect an attempt to jump will have.
-#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
 pub enum PlayerState {
     /// The player is on a wall. The number is the direction of the hit wall. The player can wall-jump.
     WallBound(i8),
@@ -201,6 +768,19 @@ pub enum PlayerState {
     Airborne,
 }
 
+/// The FNV-1a hash of some bytes, which is the same for every build and platform.
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let mut hash = OFFSET;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+    }
+    hash
+}
+
 fn collide(player: &mut Player, platforms: &[Platform]) {
     let mut collided = true;
     player.state = PlayerState::Airborne;
@@ -259,3 +839,76 @@ fn fix_position(player: &mut Player, platform: &Platform) {
         x_corrected
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f64 = 1. / 60.;
+
+    /// A game with two players, simulated for `frames` frames of walking and jumping.
+    fn simulate(physics: Physics, frames: usize) -> Game {
+        let mut game = Game {
+            physics,
+            ..Game::new()
+        };
+        game.add_player();
+        game.add_player();
+        for frame in 0..frames {
+            let x = if frame / 40 % 2 == 0 { 1 } else { -1 };
+            let y = if frame % 25 == 0 { -1 } else { 0 };
+            game.player_physics(0, (x, y), DT);
+            game.player_physics(1, (-x, 0), DT);
+        }
+        game
+    }
+
+    #[test]
+    fn fixed_physics_are_reproducible() {
+        let game = simulate(Physics::Fixed, 300);
+        assert_eq!(
+            game.state_hash(),
+            simulate(Physics::Fixed, 300).state_hash()
+        );
+
+        // every value can be represented in fixed point, so sending the state loses nothing
+        for player in game.players.iter().flatten() {
+            for v in [
+                player.pos.x,
+                player.pos.y,
+                player.velocity.x,
+                player.velocity.y,
+            ] {
+                assert_eq!(Fixed::from_f64(v).to_f64(), v);
+            }
+        }
+        let json = serde_json::to_string(&game).unwrap();
+        let mut copy: Game = serde_json::from_str(&json).unwrap();
+        let mut game = game;
+        for g in [&mut game, &mut copy] {
+            g.player_physics(0, (1, -1), DT);
+        }
+        assert_eq!(game.state_hash(), copy.state_hash());
+    }
+
+    #[test]
+    fn fixed_physics_follow_float_physics() {
+        let fixed = simulate(Physics::Fixed, 300);
+        let float = simulate(Physics::Float, 300);
+        for (a, b) in fixed
+            .players
+            .iter()
+            .flatten()
+            .zip(float.players.iter().flatten())
+        {
+            assert!(
+                a.pos.dist(b.pos) < 0.5,
+                "{:?} and {:?} diverged",
+                a.pos,
+                b.pos
+            );
+            assert_eq!(a.state, b.state);
+        }
+        assert_ne!(fixed.state_hash(), float.state_hash());
+    }
+}
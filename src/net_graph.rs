@@ -0,0 +1,213 @@
+//! A graph of network measurements over the last few seconds, shown in the client window.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::networking::Traffic;
+
+/// Samples kept of each measurement, one per frame.
+pub const HISTORY_LEN: usize = 100;
+
+/// Rates are averaged over this much time.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The measurements of one frame, as given to `NetGraph::push`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Measurements {
+    /// The measured round-trip time, or `None` before the clocks are synchronized.
+    pub rtt: Option<Duration>,
+    pub jitter: Duration,
+    /// The packets and bytes sent and received so far, which the rates are computed from.
+    pub traffic: Traffic,
+    /// The number of snapshots received so far.
+    pub snapshots: usize,
+    /// The number of movements the server has not acknowledged.
+    pub unacknowledged: usize,
+    /// How far reconciliation moved the local player.
+    pub correction: f64,
+    /// The number of snapshots in the interpolation buffer which have not been shown yet.
+    pub interpolation_depth: usize,
+}
+
+/// One measurement over time.
+pub struct Series {
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// Decimals shown of the newest sample.
+    pub precision: usize,
+    /// The samples, oldest first.
+    pub samples: VecDeque<f64>,
+}
+
+impl Series {
+    fn new(name: &'static str, unit: &'static str, precision: usize) -> Self {
+        Self {
+            name,
+            unit,
+            precision,
+            samples: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    fn push(&mut self, value: f64) {
+        if self.samples.len() == HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+    }
+
+    /// The newest sample, or zero if there are none.
+    pub fn last(&self) -> f64 {
+        self.samples.back().copied().unwrap_or(0.)
+    }
+
+    /// The largest sample, which the graph is scaled to.
+    pub fn max(&self) -> f64 {
+        self.samples.iter().copied().fold(0., f64::max)
+    }
+
+    /// The name and the newest sample, such as `RTT: 95 ms`.
+    pub fn label(&self) -> String {
+        let label = format!("{}: {:.*}", self.name, self.precision, self.last());
+        match self.unit {
+            "" => label,
+            unit => format!("{label} {unit}"),
+        }
+    }
+}
+
+/// The recent history of the measurements shown in the net graph.
+///
+/// Totals such as the bytes received are turned into rates per second, averaged over the last second.
+pub struct NetGraph {
+    series: Vec<Series>,
+    /// The traffic and snapshot totals of the frames in the last second, oldest first.
+    totals: VecDeque<(Instant, Traffic, usize)>,
+}
+
+impl NetGraph {
+    pub fn new() -> Self {
+        Self {
+            series: vec![
+                Series::new("RTT", "ms", 0),
+                Series::new("Jitter", "ms", 1),
+                Series::new("In", "B/s", 0),
+                Series::new("Out", "B/s", 0),
+                Series::new("Packets in", "/s", 0),
+                Series::new("Packets out", "/s", 0),
+                Series::new("Snapshots", "/s", 0),
+                Series::new("Unacked inputs", "", 0),
+                Series::new("Correction", "", 2),
+                Series::new("Interp. buffer", "", 0),
+            ],
+            totals: VecDeque::new(),
+        }
+    }
+
+    /// The measurements, in the order they are shown.
+    pub fn series(&self) -> &[Series] {
+        &self.series
+    }
+
+    /// Add the measurements of the frame at `now`.
+    pub fn push(&mut self, now: Instant, measurements: Measurements) {
+        self.totals
+            .push_back((now, measurements.traffic, measurements.snapshots));
+        while self
+            .totals
+            .front()
+            .is_some_and(|(time, ..)| now - *time > RATE_WINDOW)
+        {
+            self.totals.pop_front();
+        }
+
+        let (since, start, start_snapshots) = self.totals[0];
+        let elapsed = (now - since).as_secs_f64();
+        let rate = |total: usize, start: usize| {
+            if elapsed > 0. {
+                total.saturating_sub(start) as f64 / elapsed
+            } else {
+                0.
+            }
+        };
+
+        let traffic = measurements.traffic;
+        let values = [
+            measurements.rtt.unwrap_or_default().as_secs_f64() * 1000.,
+            measurements.jitter.as_secs_f64() * 1000.,
+            rate(traffic.bytes_received, start.bytes_received),
+            rate(traffic.bytes_sent, start.bytes_sent),
+            rate(traffic.packets_received, start.packets_received),
+            rate(traffic.packets_sent, start.packets_sent),
+            rate(measurements.snapshots, start_snapshots),
+            measurements.unacknowledged as f64,
+            measurements.correction,
+            measurements.interpolation_depth as f64,
+        ];
+        for (series, value) in self.series.iter_mut().zip(values) {
+            series.push(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(graph: &NetGraph, name: &str) -> f64 {
+        graph
+            .series()
+            .iter()
+            .find(|s| s.name == name)
+            .unwrap()
+            .last()
+    }
+
+    #[test]
+    fn totals_become_rates() {
+        let mut graph = NetGraph::new();
+        let start = Instant::now();
+        for i in 0..=60 {
+            let traffic = Traffic {
+                packets_received: i * 2,
+                bytes_received: i * 100,
+                ..Traffic::default()
+            };
+            let measurements = Measurements {
+                rtt: Some(Duration::from_millis(80)),
+                traffic,
+                snapshots: i / 2,
+                ..Measurements::default()
+            };
+            graph.push(start + Duration::from_millis(i as u64 * 50), measurements);
+        }
+
+        // 20 frames per second
+        assert!((value(&graph, "In") - 2000.).abs() < 1e-6);
+        assert!((value(&graph, "Packets in") - 40.).abs() < 1e-6);
+        assert!((value(&graph, "Snapshots") - 10.).abs() < 1e-6);
+        assert_eq!(value(&graph, "Out"), 0.);
+        assert!((value(&graph, "RTT") - 80.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut graph = NetGraph::new();
+        let start = Instant::now();
+        for i in 0..HISTORY_LEN + 10 {
+            let measurements = Measurements {
+                unacknowledged: i,
+                ..Measurements::default()
+            };
+            graph.push(start + Duration::from_millis(i as u64 * 16), measurements);
+        }
+
+        let unacked = &graph.series()[7];
+        assert_eq!(unacked.samples.len(), HISTORY_LEN);
+        assert_eq!(unacked.samples[0], 10.);
+        assert_eq!(unacked.max(), (HISTORY_LEN + 9) as f64);
+        assert_eq!(unacked.label(), "Unacked inputs: 109");
+    }
+}
@@ -1,30 +1,139 @@
 //! Implementations of netcode features.
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
 
-use crate::{client, model::*};
+use crate::{
+    client,
+    clock::ClockSync,
+    interpolation::{SnapshotBuffer, Underrun},
+    lockstep::{self, FrameInputs, Lockstep},
+    math::Vec2,
+    model::*,
+    rollback::{self, Rollback},
+};
+
+/// Remote players are extrapolated at most this far past the newest snapshot.
+const MAX_EXTRAPOLATION: Duration = Duration::from_millis(250);
+
+/// Predictions which differ from the server's player by more than this are counted as mispredictions.
+///
+/// Larger than the error from quantized snapshots, which predictions start from.
+const PREDICTION_TOLERANCE: f64 = 0.1;
 
 /// Initialize the `Netcode`, which implements netcode features.
-pub fn init() -> Netcode {
+///
+/// Remote players are interpolated at `interpolation_delay` behind the newest snapshot,
+/// or at a delay adapted to the measured jitter if it is `None`.
+pub fn init(
+    interpolation_delay: Option<Duration>,
+    underrun: Underrun,
+    smoothing: Smoothing,
+) -> Netcode {
     Netcode {
         movement_history: Vec::new(),
-        players_prev: Vec::new(),
-        players_current: Vec::new(),
-        server_tick_time: Duration::from_millis(100), // initial guess, this gets changed
-        server_timestamp: Instant::now(),
+        acked: 0,
+        predictions: VecDeque::new(),
+        mispredictions: Mispredictions::default(),
+        snapshots: SnapshotBuffer::new(interpolation_delay, underrun),
+        smoothing,
+        local: None,
+        error: Vec2::new(0., 0.),
+        correction: 0.,
+        clock: ClockSync::new(),
+        mode: Mode::Authoritative,
+        rollback: None,
+        lockstep: None,
+        resync: None,
+    }
+}
+
+/// Smoothing of corrections, unless configured.
+pub const DEFAULT_SMOOTHING: Smoothing = Smoothing {
+    time: Duration::from_millis(100),
+    snap_distance: 32.,
+};
+
+/// How corrections of the local player from reconciliation are shown.
+///
+/// The physics always use the corrected position. Only the displayed position is smoothed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Smoothing {
+    /// Time for the displayed error to decay to about a third, or zero to show corrections immediately.
+    pub time: Duration,
+    /// Errors larger than this are shown immediately, since smoothing them would look worse than a jump.
+    pub snap_distance: f64,
+}
+
+/// How the predictions of the local player compared with the server's states.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Mispredictions {
+    /// The number of predictions compared with the server's.
+    pub checked: usize,
+    /// The number of predictions which differed from the server's state.
+    pub count: usize,
+    /// The distance between the predicted position and the server's at the last misprediction.
+    pub last_error: f64,
+    /// The largest distance between a predicted position and the server's.
+    pub max_error: f64,
+}
+
+impl Display for Mispredictions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Mispredictions: {}/{}, error {:.2} (max {:.2})",
+            self.count, self.checked, self.last_error, self.max_error
+        )
     }
 }
 
+/// Which netcode features are enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Features {
+    pub prediction: bool,
+    pub reconciliation: bool,
+    pub interpolation: bool,
+    pub extrapolation: bool,
+    /// Show the game simulated with rollback in `Mode::Rollback`, rather than the server's final states.
+    pub rollback: bool,
+}
+
 /// Keeps track of state required to implement netcode features.
 ///
-/// Use `apply` to apply prediction, reconciliation and interpolation.
+/// Use `apply` to apply prediction, reconciliation, interpolation and extrapolation.
 /// Use `push_movement` and `update` to update the state each frame.
+///
+/// In `Mode::Rollback`, `apply` simulates all players with rollback instead,
+/// from the local movement and the inputs of the other players given to `peer_input`.
+/// In `Mode::Lockstep`, it simulates the frames confirmed by the server, given to `lockstep_frames`.
 pub struct Netcode {
     movement_history: Vec<Movement>,
-    players_prev: Vec<Player>,
-    players_current: Vec<Player>,
-    server_tick_time: Duration,
-    server_timestamp: Instant,
+    /// The id of the newest movement acknowledged by the server.
+    acked: usize,
+    /// The predicted local player after each unacknowledged movement, by movement id,
+    /// to be compared with the server's state once the movement is acknowledged.
+    predictions: VecDeque<(usize, Player)>,
+    mispredictions: Mispredictions,
+    snapshots: SnapshotBuffer,
+    smoothing: Smoothing,
+    /// The local player as of the last frame, before any correction.
+    local: Option<Player>,
+    /// The offset of the displayed local player from its actual position.
+    error: Vec2,
+    /// How far reconciliation moved the local player in the last frame.
+    correction: f64,
+    clock: ClockSync,
+    mode: Mode,
+    /// The game simulated by all peers in `Mode::Rollback`, once the first snapshot and the level have arrived.
+    rollback: Option<Rollback>,
+    /// The game simulated by all peers in `Mode::Lockstep`, once the start state and the level have arrived.
+    lockstep: Option<Lockstep>,
+    /// The state which the simulation starts from, in `Mode::Rollback` and `Mode::Lockstep`.
+    resync: Option<(u64, Vec<Option<Player>>)>,
 }
 
 impl Netcode {
@@ -37,43 +146,340 @@ impl Netcode {
         id
     }
 
-    /// Update the current player state, which is used for interpolation.
-    pub fn update(&mut self, players_current: Vec<Player>) {
-        std::mem::swap(&mut self.players_prev, &mut self.players_current);
-        self.server_tick_time = self.server_timestamp.elapsed();
-        self.server_timestamp = Instant::now();
-        self.players_current = players_current;
+    /// Up to `count` unacknowledged movements before the newest one, newest first,
+    /// to be sent again in case the messages carrying them were lost.
+    pub fn redundant(&self, count: usize) -> Vec<(i8, i8)> {
+        let earlier = self.movement_history.len().saturating_sub(1);
+        self.movement_history[..earlier]
+            .iter()
+            .rev()
+            .take(count)
+            .map(|m| m.dir)
+            .collect()
+    }
+
+    /// The number of movements which the server has not acknowledged yet.
+    pub fn unacknowledged(&self) -> usize {
+        self.movement_history.len()
+    }
+
+    /// How the predictions of the local player compared with the server's states so far.
+    pub fn mispredictions(&self) -> Mispredictions {
+        self.mispredictions
     }
 
-    /// Apply netcode features on the client side. Use the boolean arguments to specify which features to enable.
+    /// Compare the prediction of the local player after movement `ack_id` with the server's `Player::state_hash`,
+    /// and record a misprediction with the distance to the server's player if they differ.
+    ///
+    /// Predictions start from snapshots, which some codecs quantize, so they rarely match the server's exactly.
+    /// Unless the hashes match, a prediction is only wrong if it differs from the server's player
+    /// by more than `PREDICTION_TOLERANCE`.
+    ///
+    /// Each prediction is only compared once, and predictions which were never made are not compared.
+    pub fn check_prediction(&mut self, ack_id: usize, hash: u64, server: &Player) {
+        let Some(i) = self.predictions.iter().position(|(id, _)| *id == ack_id) else {
+            return;
+        };
+        let (_, predicted) = &self.predictions[i];
+        self.mispredictions.checked += 1;
+        if predicted.state_hash() != hash && !predicted.close_to(server, PREDICTION_TOLERANCE) {
+            let error = predicted.pos.dist(server.pos);
+            self.mispredictions.count += 1;
+            self.mispredictions.last_error = error;
+            self.mispredictions.max_error = self.mispredictions.max_error.max(error);
+        }
+        self.predictions.drain(..=i);
+    }
+
+    /// The current interpolation delay.
+    pub fn interpolation_delay(&self) -> Duration {
+        self.snapshots.delay()
+    }
+
+    /// The number of times interpolation ran out of snapshots.
+    pub fn underruns(&self) -> usize {
+        self.snapshots.underruns()
+    }
+
+    /// How far reconciliation moved the local player in the last frame.
+    pub fn correction(&self) -> f64 {
+        self.correction
+    }
+
+    /// The number of buffered snapshots which interpolation has not reached yet.
+    pub fn interpolation_depth(&self) -> usize {
+        self.snapshots.depth()
+    }
+
+    /// The offset of the displayed local player from its actual position, caused by corrections.
+    pub fn smoothing_error(&self) -> Vec2 {
+        self.error
+    }
+
+    /// The game as it should be shown, with the local player offset by the smoothing error.
+    pub fn displayed(&self, state: &client::State) -> Game {
+        let mut game = state.shared.clone();
+        if let Some(Some(player)) = state.player_idx.and_then(|i| game.players.get_mut(i)) {
+            player.pos += self.error;
+        }
+        game
+    }
+
+    /// The client time to send in a `ClientPacket::Ping`.
+    pub fn ping_time(&self) -> u64 {
+        self.clock.client_time(Instant::now())
+    }
+
+    /// Synchronize the clock with a `ServerPacket::Pong`.
+    pub fn pong(&mut self, client_time_us: u64, server_time_us: u64) {
+        self.clock
+            .pong(client_time_us, server_time_us, Instant::now());
+    }
+
+    /// The measured round-trip time to the server, or `None` before the clocks are synchronized.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.clock.rtt()
+    }
+
+    /// The variation of the measured round-trip time.
+    pub fn rtt_jitter(&self) -> Duration {
+        self.clock.jitter()
+    }
+
+    /// The server's clock minus the client's, in seconds, or `None` before the clocks are synchronized.
+    pub fn clock_offset(&self) -> Option<f64> {
+        self.clock.offset()
+    }
+
+    /// Set how the game is simulated, as decided by the server.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// What rollback has done so far, or `None` if it has not started.
+    pub fn rollback_stats(&self) -> Option<rollback::Stats> {
+        self.rollback.as_ref().map(Rollback::stats)
+    }
+
+    /// What lockstep has done so far, or `None` if it has not started.
+    pub fn lockstep_stats(&self) -> Option<lockstep::Stats> {
+        self.lockstep.as_ref().map(Lockstep::stats)
+    }
+
+    /// Start `Mode::Lockstep` from the state before `frame`, unless the simulation has already come further.
+    pub fn start_lockstep(&mut self, frame: u64, players: Vec<Option<Player>>) {
+        if self.lockstep.as_ref().is_none_or(|l| frame > l.frame()) {
+            self.resync = Some((frame, players));
+        }
+    }
+
+    /// Add the frames confirmed by the server in `Mode::Lockstep`, and compare the server's checksum.
+    pub fn lockstep_frames(
+        &mut self,
+        frame: u64,
+        inputs: Vec<FrameInputs>,
+        checksum: Option<(u64, u64)>,
+    ) {
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.add_frames(frame, inputs);
+            if let Some((frame, checksum)) = checksum {
+                lockstep.check(frame, checksum);
+            }
+        }
+    }
+
+    /// The packet with the local inputs which have not been simulated yet in `Mode::Lockstep`.
+    ///
+    /// All of them are sent every time, since the frames can not be simulated without them.
+    pub fn lockstep_input(&self, player_idx: usize) -> Option<ClientPacket> {
+        let lockstep = self.lockstep.as_ref()?;
+        let (frame, inputs) = lockstep.pending_inputs(player_idx)?;
+        Some(ClientPacket::LockstepInput {
+            frame,
+            inputs,
+            next_frame: lockstep.frame(),
+            checksum: lockstep.newest_checksum(),
+        })
+    }
+
+    /// Add the inputs of another player in `Mode::Rollback`, newest first, where the first one is for `frame`.
+    pub fn peer_input(&mut self, player_idx: usize, frame: u64, inputs: &[(i8, i8)]) {
+        if let Some(rollback) = &mut self.rollback {
+            rollback.add_inputs(player_idx, frame, inputs);
+        }
+    }
+
+    /// The newest inputs of the local player in `Mode::Rollback`, newest first, at most `count` of them.
+    ///
+    /// Returns the frame of the newest input along with the inputs.
+    pub fn local_inputs(&self, player_idx: usize, count: usize) -> Option<(u64, Vec<(i8, i8)>)> {
+        self.rollback.as_ref()?.recent_inputs(player_idx, count)
+    }
+
+    /// Buffer a snapshot of server `tick`, taken at server `time`, which is used for interpolation.
+    ///
+    /// In `Mode::Rollback`, the snapshot is a final state, which the simulation is corrected with instead.
+    /// In `Mode::Lockstep`, snapshots are not used.
+    pub fn update(&mut self, tick: u64, time: Duration, players: Vec<Option<Player>>) {
+        match (self.mode, &mut self.rollback) {
+            (Mode::Rollback, Some(rollback)) => rollback.correct(tick, players),
+            (Mode::Rollback, None) => self.resync = Some((tick, players)),
+            (Mode::Authoritative, _) => self.snapshots.push(tick, time, players, Instant::now()),
+            // the quantized players of snapshots would not match the exact simulation
+            (Mode::Lockstep, _) => (),
+        }
+    }
+
+    /// Apply the enabled netcode features on the client side.
     ///
     /// `move_ack_id` is the id of the last movement acknowledged by the server.
+    ///
+    /// Extrapolation takes over when interpolation runs out of snapshots,
+    /// or projects remote players forward from the newest snapshot when interpolation is disabled.
     pub fn apply(
         &mut self,
         state: &mut client::State,
         move_ack_id: usize,
         movement: (i8, i8),
-        prediction: bool,
-        reconciliation: bool,
-        interpolation: bool,
+        features: Features,
     ) {
+        match self.mode {
+            Mode::Authoritative => (),
+            Mode::Rollback => return self.roll_forward(state, movement, features.rollback),
+            Mode::Lockstep => return self.lockstep(state, movement),
+        }
+
+        // where the local player would have been without the correction
+        let uncorrected = match self.local.take() {
+            Some(player) if move_ack_id != 0 && features.reconciliation => {
+                let mut game = Game {
+                    platforms: std::mem::take(&mut state.shared.platforms),
+                    players: vec![Some(player)],
+                    physics: state.shared.physics,
+                };
+                if features.prediction {
+                    game.player_physics(0, movement, client::DELTA_TIME);
+                }
+                state.shared.platforms = game.platforms;
+                game.players[0].as_ref().map(|p| p.pos)
+            }
+            _ => None,
+        };
+
         if move_ack_id != 0 {
-            self.movement_history.retain(|m| m.id > move_ack_id);
-            if reconciliation {
+            // acks never go backwards, even if the server's snapshots arrive out of order
+            self.acked = self.acked.max(move_ack_id);
+            let acked = self.acked;
+            self.movement_history.retain(|m| m.id > acked);
+            self.predictions.retain(|(id, _)| *id > acked);
+            if features.reconciliation {
                 reconcile(state, &self.movement_history)
             };
         }
 
-        if prediction {
+        // reconciliation has already replayed the newest movement
+        if features.prediction && !(move_ack_id != 0 && features.reconciliation) {
             predict(state, movement)
         };
 
-        if interpolation {
-            let elapsed = self.server_timestamp.elapsed().as_secs_f64();
-            let tick_time = self.server_tick_time.as_secs_f64();
-            let factor = (elapsed / tick_time).min(1.);
-            interpolate(state, &self.players_prev, &self.players_current, factor);
+        let newest = self.movement_history.last().map(|m| m.id);
+        if features.prediction
+            && let Some(id) = newest.filter(|&id| self.predictions.back().is_none_or(|p| id > p.0))
+            && let Some(Some(player)) = state.player_idx.and_then(|i| state.shared.players.get(i))
+        {
+            self.predictions.push_back((id, player.clone()));
+        }
+
+        self.local = state
+            .player_idx
+            .and_then(|i| state.shared.players.get(i).cloned().flatten());
+        self.correction = 0.;
+        if let (Some(uncorrected), Some(local)) = (uncorrected, &self.local) {
+            self.error += uncorrected - local.pos;
+            self.correction = uncorrected.dist(local.pos);
+        }
+        self.error = self.smoothing.decay(self.error);
+
+        let now = Instant::now();
+        let mut ahead = None;
+        if features.interpolation
+            && let Some(players) = self.snapshots.sample(now, self.clock.sent_time(now))
+        {
+            interpolate(state, &players);
+            ahead = Some(self.snapshots.overshoot()).filter(|t| !t.is_zero());
+        } else if let Some((_, arrival)) = self.snapshots.newest() {
+            ahead = Some(now - arrival);
+        }
+
+        if features.extrapolation
+            && let Some(ahead) = ahead
+            && let Some((players, _)) = self.snapshots.newest()
+        {
+            extrapolate(state, players, ahead.min(MAX_EXTRAPOLATION));
+        }
+    }
+
+    /// Simulate the frames up to the server's current frame with rollback, using `movement` for the local player.
+    ///
+    /// The simulated game is shown if `show` is set. Otherwise the state stays as the newest snapshot.
+    fn roll_forward(&mut self, state: &mut client::State, movement: (i8, i8), show: bool) {
+        let Some(server_time) = self.clock.server_time(Instant::now()) else {
+            return;
+        };
+        if self.rollback.is_none()
+            && !state.shared.platforms.is_empty()
+            && let Some((frame, players)) = self.resync.take()
+        {
+            let game = Game {
+                platforms: state.shared.platforms.clone(),
+                players,
+                physics: state.shared.physics,
+            };
+            self.rollback = Some(Rollback::new(game, frame));
+        }
+        let Some(rollback) = &mut self.rollback else {
+            return;
+        };
+
+        let target = (server_time / client::DELTA_TIME) as u64;
+        while rollback.frame() < target {
+            if let Some(player_idx) = state.player_idx {
+                rollback.add_inputs(player_idx, rollback.frame(), &[movement]);
+            }
+            rollback.advance();
+        }
+
+        if show {
+            state.shared.players = rollback.game().players.clone();
+        }
+    }
+
+    /// Queue `movement` for the local player after the input delay, and simulate the frames confirmed so far.
+    fn lockstep(&mut self, state: &mut client::State, movement: (i8, i8)) {
+        if !state.shared.platforms.is_empty()
+            && let Some((frame, players)) = self.resync.take()
+        {
+            let game = Game {
+                platforms: state.shared.platforms.clone(),
+                players,
+                physics: state.shared.physics,
+            };
+            self.lockstep = Some(Lockstep::new(game, frame));
+        }
+        let delay = lockstep::input_delay(self.clock.rtt());
+        let Some(lockstep) = &mut self.lockstep else {
+            return;
+        };
+
+        if let Some(player_idx) = state.player_idx {
+            lockstep.queue_input(player_idx, movement, delay);
         }
+        lockstep.advance();
+        state.shared.players = lockstep.game().players.clone();
     }
 }
 
@@ -95,19 +501,374 @@ fn reconcile(state: &mut client::State, movement_history: &[Movement]) {
     }
 }
 
-fn interpolate(
-    state: &mut client::State,
-    players_prev: &[Player],
-    players_current: &[Player],
-    interpolation_factor: f64,
-) {
-    let player_idx = state.player_idx.unwrap_or(players_prev.len());
-    for (i, player) in players_prev.iter().enumerate() {
+impl Smoothing {
+    /// Decay an error by one frame, or remove it if it is too large to smooth.
+    fn decay(&self, error: Vec2) -> Vec2 {
+        if self.time.is_zero() || error.len() > self.snap_distance {
+            return Vec2::new(0., 0.);
+        }
+        error * (-client::DELTA_TIME / self.time.as_secs_f64()).exp()
+    }
+}
+
+/// Project remote players `ahead` past a snapshot, by simulating them without any input.
+fn extrapolate(state: &mut client::State, players: &[Option<Player>], ahead: Duration) {
+    let mut game = Game {
+        platforms: std::mem::take(&mut state.shared.platforms),
+        players: players.to_vec(),
+        physics: state.shared.physics,
+    };
+
+    let player_idx = state.player_idx.unwrap_or(players.len());
+    let mut remaining = ahead.as_secs_f64();
+    while remaining > 0. {
+        let dt = remaining.min(client::DELTA_TIME);
+        for i in (0..players.len()).filter(|&i| i != player_idx) {
+            game.player_physics(i, (0, 0), dt);
+        }
+        remaining -= dt;
+    }
+
+    for (i, extrapolated) in game.players.iter().enumerate() {
+        if i == player_idx {
+            continue;
+        }
+        if let (Some(extrapolated), Some(Some(player))) =
+            (extrapolated, state.shared.players.get_mut(i))
+        {
+            player.pos = extrapolated.pos;
+        }
+    }
+    state.shared.platforms = game.platforms;
+}
+
+fn interpolate(state: &mut client::State, players: &[Option<Player>]) {
+    let player_idx = state.player_idx.unwrap_or(players.len());
+    for (i, interpolated) in players.iter().enumerate() {
         if i == player_idx {
             continue;
         }
 
-        let pos_diff = players_current[i].pos - player.pos;
-        state.shared.players[i].pos = player.pos + (pos_diff * interpolation_factor);
+        let (Some(interpolated), Some(Some(player))) =
+            (interpolated, state.shared.players.get_mut(i))
+        else {
+            continue;
+        };
+
+        player.pos = interpolated.pos;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        math::Vec2,
+        networking::{self, Codec},
+    };
+
+    fn state() -> client::State {
+        let mut shared = Game::new();
+        shared.add_player();
+        shared.add_player();
+        client::State {
+            player_idx: Some(0),
+            shared,
+        }
+    }
+
+    fn smoothing(ms: u64) -> Smoothing {
+        Smoothing {
+            time: Duration::from_millis(ms),
+            snap_distance: 20.,
+        }
+    }
+
+    #[test]
+    fn corrections_are_smoothed_but_physics_is_exact() {
+        let features = Features {
+            prediction: true,
+            reconciliation: true,
+            ..Features::default()
+        };
+        let mut netcode = init(None, Underrun::Hold, smoothing(100));
+        let mut state = state();
+        netcode.apply(&mut state, 0, (0, 0), features);
+        let before = netcode.displayed(&state).players[0].as_ref().unwrap().pos;
+
+        // the server corrects the local player by 4 units
+        let id = netcode.push_movement((0, 0));
+        state.shared.players[0].as_mut().unwrap().pos += Vec2::new(4., 0.);
+        netcode.apply(&mut state, id, (0, 0), features);
+
+        let actual = state.shared.players[0].as_ref().unwrap().pos;
+        let displayed = netcode.displayed(&state).players[0].as_ref().unwrap().pos;
+        assert!(
+            (actual.x - before.x - 4.).abs() < 1e-9,
+            "the physics use the corrected position"
+        );
+        assert!(
+            displayed.x > before.x && displayed.x < actual.x,
+            "{displayed:?}"
+        );
+        assert!((displayed - actual).dist(netcode.smoothing_error()) < 1e-9);
+
+        for _ in 0..60 {
+            netcode.apply(&mut state, 0, (0, 0), features);
+        }
+        assert!(netcode.smoothing_error().len() < 0.01);
+    }
+
+    #[test]
+    fn large_corrections_snap() {
+        let features = Features {
+            prediction: true,
+            reconciliation: true,
+            ..Features::default()
+        };
+        let mut netcode = init(None, Underrun::Hold, smoothing(100));
+        let mut state = state();
+        netcode.apply(&mut state, 0, (0, 0), features);
+
+        let id = netcode.push_movement((0, 0));
+        state.shared.players[0].as_mut().unwrap().pos += Vec2::new(50., 0.);
+        netcode.apply(&mut state, id, (0, 0), features);
+        assert_eq!(netcode.smoothing_error(), Vec2::new(0., 0.));
+    }
+
+    #[test]
+    fn reconciliation_replays_the_newest_movement_once() {
+        let features = Features {
+            prediction: true,
+            reconciliation: true,
+            ..Features::default()
+        };
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        let mut state = state();
+        let mut server = state.shared.clone();
+
+        let id = netcode.push_movement((1, 0));
+        netcode.apply(&mut state, 0, (1, 0), features);
+        server.player_physics(0, (1, 0), client::DELTA_TIME);
+
+        // the snapshot acknowledging the first movement arrives along with the second one
+        netcode.push_movement((1, 0));
+        state.shared.players = server.players.clone();
+        netcode.apply(&mut state, id, (1, 0), features);
+        server.player_physics(0, (1, 0), client::DELTA_TIME);
+
+        assert_eq!(state.shared.players[0], server.players[0]);
+    }
+
+    #[test]
+    fn mispredictions_are_detected_by_hash() {
+        let features = Features {
+            prediction: true,
+            ..Features::default()
+        };
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        let mut state = state();
+
+        let first = netcode.push_movement((1, 0));
+        netcode.apply(&mut state, 0, (1, 0), features);
+        let predicted = state.shared.players[0].clone().unwrap();
+        let second = netcode.push_movement((1, 0));
+        netcode.apply(&mut state, 0, (1, 0), features);
+
+        netcode.check_prediction(first, predicted.state_hash(), &predicted);
+        assert_eq!(netcode.mispredictions().checked, 1);
+        assert_eq!(netcode.mispredictions().count, 0);
+
+        // the server did not simulate the second movement the same way
+        let mut server = state.shared.players[0].clone().unwrap();
+        server.pos += Vec2::new(-3., 4.);
+        netcode.check_prediction(second, server.state_hash(), &server);
+        netcode.check_prediction(second, server.state_hash(), &server);
+        let mispredictions = netcode.mispredictions();
+        assert_eq!((mispredictions.checked, mispredictions.count), (2, 1));
+        assert!((mispredictions.max_error - 5.).abs() < 1e-9);
+    }
+
+    /// The players as the client decodes them from a snapshot sent with `networking::Binary`.
+    fn through_binary(players: &[Option<Player>]) -> Vec<Option<Player>> {
+        let response = ServerResponse {
+            ack_id: 0,
+            player_hash: None,
+            level_hash: 0,
+            sequence: 1,
+            tick: 1,
+            time_us: 0,
+            input: InputStatus::default(),
+            snapshot: Snapshot::Full(players.to_vec()),
+        };
+        let codec = networking::Binary;
+        let bytes = codec.encode_server(&ServerPacket::Snapshot(response));
+        match codec.decode_server(&bytes) {
+            Some(ServerPacket::Snapshot(ServerResponse {
+                snapshot: Snapshot::Full(players),
+                ..
+            })) => players,
+            packet => panic!("{packet:?}"),
+        }
+    }
+
+    #[test]
+    fn quantized_snapshots_are_not_mispredictions() {
+        let features = Features {
+            prediction: true,
+            reconciliation: true,
+            ..Features::default()
+        };
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        let mut state = state();
+        let mut server = state.shared.clone();
+        let player = server.players[0].as_mut().unwrap();
+        player.pos += Vec2::new(0.3, -0.17);
+        player.velocity = Vec2::new(1.01, -2.345);
+
+        state.shared.players = through_binary(&server.players);
+        let id = netcode.push_movement((1, 0));
+        netcode.apply(&mut state, 0, (1, 0), features);
+        server.player_physics(0, (1, 0), client::DELTA_TIME);
+
+        let exact = server.players[0].as_ref().unwrap();
+        let predicted = state.shared.players[0].as_ref().unwrap();
+        assert_ne!(predicted.state_hash(), exact.state_hash());
+        let decoded = through_binary(&server.players);
+        netcode.check_prediction(id, exact.state_hash(), decoded[0].as_ref().unwrap());
+        assert_eq!(netcode.mispredictions().checked, 1);
+        assert_eq!(netcode.mispredictions().count, 0);
+    }
+
+    #[test]
+    fn acks_are_monotonic() {
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        let mut state = state();
+        for _ in 0..5 {
+            netcode.push_movement((1, 0));
+        }
+
+        netcode.apply(&mut state, 4, (0, 0), Features::default());
+        netcode.apply(&mut state, 2, (0, 0), Features::default());
+        assert_eq!(netcode.unacknowledged(), 1);
+        assert_eq!(netcode.acked, 4);
+    }
+
+    #[test]
+    fn redundant_movements_are_the_newest_unacknowledged() {
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        let mut state = state();
+        assert!(netcode.redundant(3).is_empty());
+
+        for x in 0..5 {
+            netcode.push_movement((x, 0));
+        }
+        assert_eq!(netcode.redundant(3), vec![(3, 0), (2, 0), (1, 0)]);
+
+        netcode.apply(&mut state, 3, (0, 0), Features::default());
+        assert_eq!(netcode.redundant(3), vec![(3, 0)]);
+    }
+
+    #[test]
+    fn rollback_starts_from_the_newest_snapshot_at_the_server_time() {
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        netcode.set_mode(Mode::Rollback);
+        let mut state = state();
+        let features = Features {
+            rollback: true,
+            ..Features::default()
+        };
+
+        // the server is one second, or 60 frames, in
+        netcode.pong(netcode.ping_time(), 1_000_000);
+        netcode.apply(&mut state, 0, (1, 0), features);
+        assert_eq!(netcode.rollback_stats(), None, "waits for a snapshot");
+
+        let players = state.shared.players.clone();
+        netcode.update(50, Duration::ZERO, players);
+        netcode.apply(&mut state, 0, (1, 0), features);
+
+        let (newest, inputs) = netcode.local_inputs(0, 20).unwrap();
+        assert!(newest >= 59, "{newest}");
+        assert_eq!(inputs.len(), (newest - 49) as usize);
+        assert!(state.shared.players[0].as_ref().unwrap().velocity.x > 0.);
+        assert_eq!(netcode.unacknowledged(), 0);
+    }
+
+    #[test]
+    fn lockstep_waits_for_the_confirmed_frames() {
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        netcode.set_mode(Mode::Lockstep);
+        let mut state = state();
+        let start = state.shared.players.clone();
+
+        netcode.start_lockstep(100, start.clone());
+        netcode.apply(&mut state, 0, (1, 0), Features::default());
+        let Some(ClientPacket::LockstepInput {
+            frame, next_frame, ..
+        }) = netcode.lockstep_input(0)
+        else {
+            panic!("the local input is sent");
+        };
+        assert_eq!((frame, next_frame), (100, 100));
+        assert_eq!(state.shared.players, start, "stalls without the inputs");
+        assert_eq!(netcode.lockstep_stats().map(|s| s.stalls), Some(1));
+
+        netcode.lockstep_frames(100, vec![vec![Some((1, 0)), Some((0, 0))]], None);
+        netcode.apply(&mut state, 0, (1, 0), Features::default());
+        assert!(state.shared.players[0].as_ref().unwrap().velocity.x > 0.);
+
+        // an older start state does not restart the simulation
+        netcode.start_lockstep(100, start);
+        netcode.apply(&mut state, 0, (1, 0), Features::default());
+        assert!(state.shared.players[0].as_ref().unwrap().velocity.x > 0.);
+    }
+
+    #[test]
+    fn extrapolation_moves_remote_players_only() {
+        let mut state = state();
+        let mut players = state.shared.players.clone();
+        for player in players.iter_mut().flatten() {
+            player.velocity = Vec2::new(60., 0.);
+            player.state = PlayerState::Airborne;
+        }
+
+        let local = state.shared.players[0].clone();
+        let remote = players[1].clone().unwrap();
+        extrapolate(&mut state, &players, Duration::from_millis(100));
+
+        assert_eq!(state.shared.players[0], local);
+        let moved = state.shared.players[1].as_ref().unwrap().pos - remote.pos;
+        assert!(moved.x > 5., "{moved:?}");
+        assert!(moved.y > 0., "falls with gravity");
+        assert!(!state.shared.platforms.is_empty());
+    }
+
+    #[test]
+    fn extrapolation_is_capped() {
+        let mut netcode = init(None, Underrun::Hold, smoothing(0));
+        let mut state = state();
+        let mut players = state.shared.players.clone();
+        if let Some(player) = &mut players[1] {
+            player.velocity = Vec2::new(60., 0.);
+            player.state = PlayerState::Airborne;
+        }
+        netcode.snapshots.push(
+            1,
+            Duration::ZERO,
+            players.clone(),
+            Instant::now() - Duration::from_secs(10),
+        );
+
+        let features = Features {
+            extrapolation: true,
+            ..Features::default()
+        };
+        netcode.apply(&mut state, 0, (0, 0), features);
+        let far = state.shared.players[1].as_ref().unwrap().pos;
+
+        let mut capped = self::state();
+        extrapolate(&mut capped, &players, MAX_EXTRAPOLATION);
+        assert_eq!(capped.shared.players[1].as_ref().unwrap().pos, far);
     }
 }
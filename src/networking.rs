@@ -1,48 +1,117 @@
-//! Wrappers around UDP sockets, used for server-client communication and simulation of ping delay.
+//! Wrappers around UDP sockets, used for server-client communication and simulation of bad network conditions.
+//!
+//! Also contains the `Codec`s used to turn packets into bytes and back.
+
+mod binary;
+mod conditioner;
+mod delay;
+
+pub use binary::Binary;
+pub use conditioner::{Conditions, Jitter};
 
 use std::{
+    collections::HashMap,
     io, net,
-    sync::{
-        Arc,
-        atomic::{AtomicU64, Ordering},
-        mpsc,
-    },
+    sync::{Arc, Mutex, mpsc},
     thread,
-    time::Duration,
+    time::Instant,
+};
+
+use conditioner::Link;
+use delay::{DelayQueue, Schedule};
+
+use crate::{
+    math::Rng,
+    model::{ClientPacket, ServerPacket},
 };
 
+/// A wire format for the packets exchanged between the client and the server.
+///
+/// Decoding returns `None` if the bytes are not a valid packet.
+pub trait Codec {
+    /// Encode a packet sent by the client.
+    fn encode_client(&self, packet: &ClientPacket) -> Vec<u8>;
+    /// Decode a packet sent by the client.
+    fn decode_client(&self, bytes: &[u8]) -> Option<ClientPacket>;
+    /// Encode a packet sent by the server.
+    fn encode_server(&self, packet: &ServerPacket) -> Vec<u8>;
+    /// Decode a packet sent by the server.
+    fn decode_server(&self, bytes: &[u8]) -> Option<ServerPacket>;
+}
+
+/// Look up a codec by name. Both `"json"` and `"binary"` are available.
+pub fn codec(name: &str) -> Option<Box<dyn Codec>> {
+    match name {
+        "json" => Some(Box::new(Json)),
+        "binary" => Some(Box::new(Binary)),
+        _ => None,
+    }
+}
+
+/// Human-readable codec using `serde_json`.
+pub struct Json;
+
+impl Codec for Json {
+    fn encode_client(&self, packet: &ClientPacket) -> Vec<u8> {
+        serde_json::to_vec(packet).unwrap()
+    }
+
+    fn decode_client(&self, bytes: &[u8]) -> Option<ClientPacket> {
+        serde_json::from_slice(bytes).ok()
+    }
+
+    fn encode_server(&self, packet: &ServerPacket) -> Vec<u8> {
+        serde_json::to_vec(packet).unwrap()
+    }
+
+    fn decode_server(&self, bytes: &[u8]) -> Option<ServerPacket> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
 fn spawn_sender(
     socket: net::UdpSocket,
     tx: mpsc::Sender<Box<[u8]>>,
-    ping_ms: Arc<AtomicU64>,
+    link: Arc<Mutex<Link>>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
+        let queue = DelayQueue::spawn(move |data| {
+            let _ = tx.send(data);
+        });
         let mut buf = [0; u16::MAX as _];
         loop {
             if let Ok(read) = socket.recv(&mut buf) {
-                let delay = load_delay(&ping_ms);
-                let tx_ref = tx.clone();
-                thread::spawn(move || {
-                    thread::sleep(delay);
-                    let boxed = Box::from(&buf[..read]);
-                    let _ = tx_ref.send(boxed);
-                });
+                let dues = link.lock().unwrap().schedule(read, Instant::now());
+                for due in dues {
+                    queue.push(due, Box::from(&buf[..read]));
+                }
             }
             // if server is not running, busy wait
         }
     })
 }
 
-fn load_delay(ms: &AtomicU64) -> Duration {
-    let delay_ms = ms.load(Ordering::Relaxed) / 2;
-    Duration::from_millis(delay_ms)
+/// Totals of the packets and bytes a `Client` has sent and received.
+///
+/// Outgoing packets are counted when they are sent, even if the simulated network conditions drop them,
+/// and incoming packets when they are received, after the simulated network conditions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub packets_sent: usize,
+    pub bytes_sent: usize,
+    pub packets_received: usize,
+    pub bytes_received: usize,
 }
 
-/// Wrapper used by the client to receive server responses and simulate ping delay.
+/// Wrapper used by the client to receive server responses and simulate bad network conditions.
 pub struct Client {
-    ping_ms: Arc<AtomicU64>,
+    incoming: Arc<Mutex<Link>>,
+    outgoing: Mutex<Link>,
+    /// Outgoing packets waiting for their simulated delay.
+    delayed: DelayQueue<Vec<u8>>,
     socket: net::UdpSocket,
     receiver: mpsc::Receiver<Box<[u8]>>,
+    traffic: Mutex<Traffic>,
 }
 
 impl Client {
@@ -51,6 +120,7 @@ impl Client {
     /// Create a new socket and connect to the remote address.
     ///
     /// The specified ping will be used to delay incoming and outgoing packets.
+    /// Other network conditions can be simulated with `set_conditions`.
     pub fn connect<A>(remote: A, simulated_ping_ms: u64) -> io::Result<Client>
     where
         A: net::ToSocketAddrs,
@@ -58,72 +128,212 @@ impl Client {
         let socket = net::UdpSocket::bind((net::Ipv4Addr::UNSPECIFIED, Self::PORT))?;
         socket.connect(remote)?;
 
+        let conditions = Conditions {
+            ping_ms: simulated_ping_ms,
+            ..Conditions::NONE
+        };
+        let mut rng = Rng::from_time();
+        let incoming = Arc::new(Mutex::new(Link::new(conditions, Rng::new(rng.next_u64()))));
+        let outgoing = Mutex::new(Link::new(conditions, Rng::new(rng.next_u64())));
+
         let (tx, receiver) = mpsc::channel();
-        let ping_ms = Arc::new(AtomicU64::new(simulated_ping_ms));
         let socket_ref = socket.try_clone()?;
 
-        spawn_sender(socket_ref, tx, Arc::clone(&ping_ms));
+        spawn_sender(socket_ref, tx, Arc::clone(&incoming));
+
+        let socket_ref = socket.try_clone()?;
+        let delayed = DelayQueue::spawn(move |data: Vec<u8>| {
+            let _ = socket_ref.send(&data);
+        });
 
         Ok(Self {
-            ping_ms,
+            incoming,
+            outgoing,
+            delayed,
             socket,
             receiver,
+            traffic: Mutex::new(Traffic::default()),
         })
     }
 
-    /// Set the value used for simulated packet delays.
-    pub fn set_ping(&self, ms: u64) {
-        self.ping_ms.store(ms, Ordering::Relaxed);
+    /// The simulated network conditions.
+    pub fn conditions(&self) -> Conditions {
+        self.outgoing.lock().unwrap().conditions()
+    }
+
+    /// Set the simulated network conditions, applied to both incoming and outgoing packets.
+    pub fn set_conditions(&self, conditions: Conditions) {
+        self.incoming.lock().unwrap().set_conditions(conditions);
+        self.outgoing.lock().unwrap().set_conditions(conditions);
+    }
+
+    /// The packets and bytes sent and received so far.
+    pub fn traffic(&self) -> Traffic {
+        *self.traffic.lock().unwrap()
     }
 
     /// Iterate over all pending packets from the server.
     pub fn recv(&self) -> impl Iterator<Item = Box<[u8]>> {
-        self.receiver.try_iter()
+        self.receiver.try_iter().inspect(|data| {
+            let mut traffic = self.traffic.lock().unwrap();
+            traffic.packets_received += 1;
+            traffic.bytes_received += data.len();
+        })
     }
 
     /// Send a packet to the server.
-    pub fn send(&self, msg: &impl serde::Serialize) -> io::Result<()> {
-        let socket = self.socket.try_clone()?;
-        let serialized = serde_json::to_vec(msg).unwrap();
-        let delay = load_delay(&self.ping_ms);
-        thread::spawn(move || {
-            thread::sleep(delay);
-            let _ = socket.send(&serialized);
-        });
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.count_sent(data);
+        let dues = self
+            .outgoing
+            .lock()
+            .unwrap()
+            .schedule(data.len(), Instant::now());
+        for due in dues {
+            self.delayed.push(due, data.to_vec());
+        }
         Ok(())
     }
+
+    /// Send a packet to the server immediately, without simulated network conditions.
+    ///
+    /// Used for packets sent right before the client shuts down.
+    pub fn send_immediately(&self, data: &[u8]) -> io::Result<()> {
+        self.count_sent(data);
+        self.socket.send(data).map(drop)
+    }
+
+    fn count_sent(&self, data: &[u8]) {
+        let mut traffic = self.traffic.lock().unwrap();
+        traffic.packets_sent += 1;
+        traffic.bytes_sent += data.len();
+    }
 }
 
 /// Wrapper used by the server send state to clients and receive messages.
 ///
 /// None of the functions in this implementation will block the thread while waiting to send or receive packets.
+/// Bad network conditions can be simulated separately for each client.
 pub struct Server {
     socket: net::UdpSocket,
     buf: Box<[u8]>,
+    rng: Rng,
+    /// Conditions for clients without conditions of their own.
+    default_conditions: Conditions,
+    conditions: HashMap<net::SocketAddr, Conditions>,
+    /// The incoming and outgoing links of each client.
+    links: HashMap<net::SocketAddr, (Link, Link)>,
+    /// Received packets waiting for their simulated delay.
+    pending: Schedule<(Box<[u8]>, net::SocketAddr)>,
+    /// Outgoing packets waiting for their simulated delay.
+    delayed: DelayQueue<(Vec<u8>, net::SocketAddr)>,
 }
 
 impl Server {
     /// Create a socket and bind it to the specified host address.
-    pub fn bind(host: net::Ipv4Addr, port: u16) -> io::Result<Self> {
-        let socket = net::UdpSocket::bind((host, port))?;
+    pub fn bind<A>(addr: A) -> io::Result<Self>
+    where
+        A: net::ToSocketAddrs,
+    {
+        let socket = net::UdpSocket::bind(addr)?;
         socket.set_nonblocking(true)?;
 
         let buf = std::iter::repeat_n(0, u16::MAX as _).collect();
 
-        Ok(Self { socket, buf })
+        let socket_ref = socket.try_clone()?;
+        let delayed = DelayQueue::spawn(move |(data, addr): (Vec<u8>, net::SocketAddr)| {
+            let _ = socket_ref.send_to(&data, addr);
+        });
+
+        Ok(Self {
+            socket,
+            buf,
+            rng: Rng::from_time(),
+            default_conditions: Conditions::NONE,
+            conditions: HashMap::new(),
+            links: HashMap::new(),
+            pending: Schedule::default(),
+            delayed,
+        })
+    }
+
+    /// The simulated network conditions for clients without conditions of their own.
+    pub fn default_conditions(&self) -> Conditions {
+        self.default_conditions
+    }
+
+    /// Set the simulated network conditions for clients without conditions of their own.
+    pub fn set_default_conditions(&mut self, conditions: Conditions) {
+        self.default_conditions = conditions;
+    }
+
+    /// The simulated network conditions for one client.
+    pub fn conditions(&self, addr: net::SocketAddr) -> Conditions {
+        self.conditions
+            .get(&addr)
+            .copied()
+            .unwrap_or(self.default_conditions)
+    }
+
+    /// Set the simulated network conditions for one client, applied to packets in both directions.
+    pub fn set_conditions(&mut self, addr: net::SocketAddr, conditions: Conditions) {
+        self.conditions.insert(addr, conditions);
+    }
+
+    /// Forget a client, dropping its links and its simulated network conditions.
+    ///
+    /// Used when a client leaves or is not accepted, so that links do not pile up,
+    /// and a client connecting again from the same address starts afresh.
+    pub fn forget(&mut self, addr: net::SocketAddr) {
+        self.conditions.remove(&addr);
+        self.links.remove(&addr);
+    }
+
+    /// The incoming and outgoing links for a client, updated with its current conditions.
+    fn link(&mut self, addr: net::SocketAddr) -> &mut (Link, Link) {
+        let conditions = self.conditions(addr);
+        let rng = &mut self.rng;
+        let link = self.links.entry(addr).or_insert_with(|| {
+            let incoming = Link::new(conditions, Rng::new(rng.next_u64()));
+            let outgoing = Link::new(conditions, Rng::new(rng.next_u64()));
+            (incoming, outgoing)
+        });
+        link.0.set_conditions(conditions);
+        link.1.set_conditions(conditions);
+        link
     }
 
     /// Receive one packet from a client.
     ///
-    /// Returns the bytes received and the origin of the packet.
-    pub fn recv(&mut self) -> io::Result<(&[u8], net::SocketAddr)> {
-        let (read, origin) = self.socket.recv_from(&mut self.buf)?;
-        Ok((&self.buf[..read], origin))
+    /// Returns the bytes received and the origin of the packet,
+    /// or an error of kind `WouldBlock` if no packet is due yet.
+    pub fn recv(&mut self) -> io::Result<(Box<[u8]>, net::SocketAddr)> {
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((read, origin)) => {
+                    let data: Box<[u8]> = Box::from(&self.buf[..read]);
+                    let dues = self.link(origin).0.schedule(read, Instant::now());
+                    for due in dues {
+                        self.pending.push(due, (data.clone(), origin));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.pending
+            .pop_due(Instant::now())
+            .ok_or_else(|| io::ErrorKind::WouldBlock.into())
     }
 
     /// Send one packet to the specified client address.
-    pub fn send<A: net::ToSocketAddrs>(&self, data: &[u8], addr: A) -> io::Result<()> {
-        self.socket.send_to(data, addr).map(drop)
+    pub fn send(&mut self, data: &[u8], addr: net::SocketAddr) -> io::Result<()> {
+        let dues = self.link(addr).1.schedule(data.len(), Instant::now());
+        for due in dues {
+            self.delayed.push(due, (data.to_vec(), addr));
+        }
+        Ok(())
     }
 }
 
@@ -135,7 +345,7 @@ mod tests {
     fn recv_from_server(server: &mut Server) -> io::Result<Box<[u8]>> {
         loop {
             match server.recv() {
-                Ok((data, _)) => return Ok(data.into()),
+                Ok((data, _)) => return Ok(data),
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
                 Err(e) => return Err(e),
             }
@@ -144,20 +354,20 @@ mod tests {
 
     #[test]
     fn test_client_server_communication() {
-        let mut server = Server::bind(net::Ipv4Addr::LOCALHOST, 8080).unwrap();
+        let mut server = Server::bind((net::Ipv4Addr::LOCALHOST, 8080)).unwrap();
         let client = Client::connect((net::Ipv4Addr::LOCALHOST, 8080), 100).unwrap();
 
         let msg = b"very important test data";
 
         let start = std::time::Instant::now();
-        client.send(&msg).unwrap();
+        client.send(msg).unwrap();
 
         let received_by_server = recv_from_server(&mut server).unwrap();
         let elapsed = start.elapsed();
 
-        let received: Box<[u8]> = serde_json::from_slice(&received_by_server).unwrap();
-
-        assert_eq!(&received[..], &msg[..]);
+        assert_eq!(&received_by_server[..], &msg[..]);
+        let traffic = client.traffic();
+        assert_eq!((traffic.packets_sent, traffic.bytes_sent), (1, msg.len()));
         assert!(
             elapsed.as_millis() >= 50,
             "Expected at least 50ms delay, got {:?}",
@@ -165,14 +375,82 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_server_conditions() {
+        let mut server = Server::bind((net::Ipv4Addr::LOCALHOST, 8081)).unwrap();
+        let client = Client::connect((net::Ipv4Addr::LOCALHOST, 8081), 0).unwrap();
+
+        server.set_default_conditions(Conditions {
+            ping_ms: 100,
+            ..Conditions::NONE
+        });
+
+        let start = std::time::Instant::now();
+        client.send(b"data").unwrap();
+        let (_, origin) = loop {
+            match server.recv() {
+                Ok(received) => break received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => panic!("{e}"),
+            }
+        };
+        assert!(start.elapsed().as_millis() >= 50);
+
+        let lossy = Conditions {
+            loss_percent: 100,
+            ..Conditions::NONE
+        };
+        server.set_conditions(origin, lossy);
+        assert_eq!(server.conditions(origin), lossy);
+        assert_eq!(server.default_conditions().ping_ms, 100);
+
+        server.forget(origin);
+        assert_eq!(server.conditions(origin).ping_ms, 100);
+        assert!(server.links.is_empty());
+    }
+
     #[test]
     fn test_client_set_ping() {
         let client = Client::connect((net::Ipv4Addr::LOCALHOST, 8080), 100).unwrap();
 
-        client.set_ping(200);
-        assert_eq!(client.ping_ms.load(Ordering::Relaxed), 200);
+        let conditions = client.conditions();
+
+        client.set_conditions(Conditions {
+            ping_ms: 200,
+            ..conditions
+        });
+        assert_eq!(client.conditions().ping_ms, 200);
 
-        client.set_ping(50);
-        assert_eq!(client.ping_ms.load(Ordering::Relaxed), 50);
+        client.set_conditions(Conditions {
+            ping_ms: 50,
+            ..conditions
+        });
+        assert_eq!(client.conditions().ping_ms, 50);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let packet = ClientPacket::Input(crate::model::Message {
+            id: 7,
+            x: -1,
+            y: 0,
+            snapshot_ack: 2,
+            redundant: vec![(1, 0)],
+        });
+        let bytes = Json.encode_client(&packet);
+        assert_eq!(Json.decode_client(&bytes), Some(packet));
+
+        let packet = ServerPacket::Snapshot(crate::model::ServerResponse {
+            ack_id: 7,
+            player_hash: Some(1),
+            level_hash: 1,
+            sequence: 1,
+            tick: 1,
+            time_us: 16_667,
+            input: crate::model::InputStatus::default(),
+            snapshot: crate::model::Snapshot::Full(vec![Some(crate::model::Player::new())]),
+        });
+        let bytes = Json.encode_server(&packet);
+        assert_eq!(Json.decode_server(&bytes), Some(packet));
     }
 }
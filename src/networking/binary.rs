@@ -0,0 +1,892 @@
+//! A compact binary codec.
+//!
+//! Integers are sent as fixed-width little-endian values,
+//! and vectors are quantized to 16-bit fixed-point numbers.
+
+use crate::{math::Vec2, model::*};
+
+use super::Codec;
+
+/// Number of steps per unit used when quantizing vectors.
+const VEC2_SCALE: f64 = 32.;
+
+/// Compact binary codec, with fixed-width integers and quantized vectors.
+///
+/// Quantization is lossy: vector components are rounded to the nearest 1/32
+/// and clamped to the range of an `i16`.
+pub struct Binary;
+
+impl Codec for Binary {
+    fn encode_client(&self, packet: &ClientPacket) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.client_packet(packet);
+        w.0
+    }
+
+    fn decode_client(&self, bytes: &[u8]) -> Option<ClientPacket> {
+        let mut r = Reader(bytes);
+        let packet = r.client_packet()?;
+        r.finish(packet)
+    }
+
+    fn encode_server(&self, packet: &ServerPacket) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.server_packet(packet);
+        w.0
+    }
+
+    fn decode_server(&self, bytes: &[u8]) -> Option<ServerPacket> {
+        let mut r = Reader(bytes);
+        let packet = r.server_packet()?;
+        r.finish(packet)
+    }
+}
+
+fn quantize(value: f64) -> i16 {
+    (value * VEC2_SCALE)
+        .round()
+        .clamp(i16::MIN as _, i16::MAX as _) as i16
+}
+
+fn dequantize(value: i16) -> f64 {
+    value as f64 / VEC2_SCALE
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn i8(&mut self, v: i8) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    fn i16(&mut self, v: i16) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    /// Sequence numbers and indices are sent as 32-bit integers.
+    fn usize(&mut self, v: usize) {
+        self.u32(u32::try_from(v).expect("too large for a 32-bit integer"));
+    }
+
+    /// A float quantized to an `i16` in steps of 1/32, which is lossy.
+    ///
+    /// Values are rounded to the nearest step and clamped to about ±1024.
+    /// Use `exact` for values which have to arrive unchanged.
+    fn quantized(&mut self, v: f64) {
+        self.i16(quantize(v));
+    }
+
+    /// A float without quantization.
+    fn exact(&mut self, v: f64) {
+        self.u64(v.to_bits());
+    }
+
+    fn vec2(&mut self, v: Vec2) {
+        self.quantized(v.x);
+        self.quantized(v.y);
+    }
+
+    /// Lengths are sent as 16-bit integers, so longer sequences can not be sent.
+    fn len(&mut self, len: usize) {
+        self.u16(u16::try_from(len).expect("too many entries for a 16-bit length"));
+    }
+
+    fn message(&mut self, m: &Message) {
+        self.usize(m.id);
+        self.i8(m.x);
+        self.i8(m.y);
+        self.usize(m.snapshot_ack);
+        self.inputs(&m.redundant);
+    }
+
+    fn inputs(&mut self, inputs: &[(i8, i8)]) {
+        self.len(inputs.len());
+        for &(x, y) in inputs {
+            self.i8(x);
+            self.i8(y);
+        }
+    }
+
+    fn mode(&mut self, mode: Mode) {
+        self.u8(match mode {
+            Mode::Authoritative => 0,
+            Mode::Rollback => 1,
+            Mode::Lockstep => 2,
+        });
+    }
+
+    fn physics(&mut self, physics: Physics) {
+        self.u8(match physics {
+            Physics::Float => 0,
+            Physics::Fixed => 1,
+        });
+    }
+
+    fn frame_inputs(&mut self, inputs: &[Option<(i8, i8)>]) {
+        self.len(inputs.len());
+        for input in inputs {
+            match input {
+                Some((x, y)) => {
+                    self.u8(1);
+                    self.i8(*x);
+                    self.i8(*y);
+                }
+                None => self.u8(0),
+            }
+        }
+    }
+
+    fn checksum(&mut self, checksum: Option<(u64, u64)>) {
+        match checksum {
+            Some((frame, checksum)) => {
+                self.u8(1);
+                self.u64(frame);
+                self.u64(checksum);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn client_packet(&mut self, packet: &ClientPacket) {
+        match packet {
+            ClientPacket::Input(m) => {
+                self.u8(0);
+                self.message(m);
+            }
+            ClientPacket::Disconnect => self.u8(1),
+            ClientPacket::LevelRequest => self.u8(2),
+            ClientPacket::Connect { protocol_version } => {
+                self.u8(3);
+                self.u32(*protocol_version);
+            }
+            ClientPacket::Ping { client_time_us } => {
+                self.u8(4);
+                self.u64(*client_time_us);
+            }
+            ClientPacket::PeerInput { frame, inputs } => {
+                self.u8(5);
+                self.u64(*frame);
+                self.inputs(inputs);
+            }
+            ClientPacket::LockstepInput {
+                frame,
+                inputs,
+                next_frame,
+                checksum,
+            } => {
+                self.u8(6);
+                self.u64(*frame);
+                self.inputs(inputs);
+                self.u64(*next_frame);
+                self.checksum(*checksum);
+            }
+        }
+    }
+
+    fn server_packet(&mut self, packet: &ServerPacket) {
+        match packet {
+            ServerPacket::Welcome {
+                platforms,
+                level_hash,
+                physics,
+            } => {
+                self.u8(0);
+                self.len(platforms.len());
+                for platform in platforms {
+                    self.platform(platform);
+                }
+                self.u64(*level_hash);
+                self.physics(*physics);
+            }
+            ServerPacket::Snapshot(response) => {
+                self.u8(1);
+                self.response(response);
+            }
+            ServerPacket::Accept {
+                player_idx,
+                max_players,
+                mode,
+            } => {
+                self.u8(2);
+                self.usize(*player_idx);
+                self.len(*max_players);
+                self.mode(*mode);
+            }
+            ServerPacket::Reject(RejectReason::VersionMismatch { server_version }) => {
+                self.u8(3);
+                self.u32(*server_version);
+            }
+            ServerPacket::Reject(RejectReason::ServerFull { max_players }) => {
+                self.u8(4);
+                self.len(*max_players);
+            }
+            ServerPacket::Pong {
+                client_time_us,
+                server_time_us,
+            } => {
+                self.u8(5);
+                self.u64(*client_time_us);
+                self.u64(*server_time_us);
+            }
+            ServerPacket::PeerInput {
+                player_idx,
+                frame,
+                inputs,
+            } => {
+                self.u8(6);
+                self.usize(*player_idx);
+                self.u64(*frame);
+                self.inputs(inputs);
+            }
+            ServerPacket::LockstepStart { frame, players } => {
+                self.u8(7);
+                self.u64(*frame);
+                self.len(players.len());
+                for player in players {
+                    match player {
+                        Some(player) => {
+                            self.u8(1);
+                            self.exact_player(player);
+                        }
+                        None => self.u8(0),
+                    }
+                }
+            }
+            ServerPacket::LockstepInputs {
+                frame,
+                inputs,
+                checksum,
+            } => {
+                self.u8(8);
+                self.u64(*frame);
+                self.len(inputs.len());
+                for inputs in inputs {
+                    self.frame_inputs(inputs);
+                }
+                self.checksum(*checksum);
+            }
+        }
+    }
+
+    fn response(&mut self, r: &ServerResponse) {
+        self.usize(r.ack_id);
+        match r.player_hash {
+            Some(hash) => {
+                self.u8(1);
+                self.u64(hash);
+            }
+            None => self.u8(0),
+        }
+        self.u64(r.level_hash);
+        self.usize(r.sequence);
+        self.u64(r.tick);
+        self.u64(r.time_us);
+        self.input_status(&r.input);
+        self.snapshot(&r.snapshot);
+    }
+
+    fn input_status(&mut self, status: &InputStatus) {
+        self.len(status.depth);
+        self.len(status.target);
+        self.usize(status.underruns);
+        self.usize(status.overruns);
+    }
+
+    fn snapshot(&mut self, snapshot: &Snapshot) {
+        match snapshot {
+            Snapshot::Full(players) => {
+                self.u8(0);
+                self.len(players.len());
+                for player in players {
+                    match player {
+                        Some(player) => {
+                            self.u8(1);
+                            self.player(player);
+                        }
+                        None => self.u8(0),
+                    }
+                }
+            }
+            Snapshot::Delta {
+                baseline,
+                len,
+                changes,
+            } => {
+                self.u8(1);
+                self.usize(*baseline);
+                self.len(*len);
+                self.len(changes.len());
+                for delta in changes {
+                    self.len(delta.player_idx);
+                    self.player_change(&delta.change);
+                }
+            }
+        }
+    }
+
+    /// Changed fields are preceded by a byte of flags telling which fields are present.
+    fn player_change(&mut self, change: &PlayerChange) {
+        let PlayerChange::Changed {
+            pos,
+            velocity,
+            size,
+            state,
+        } = change
+        else {
+            self.u8(0);
+            return;
+        };
+
+        let flags = 1
+            | (pos.is_some() as u8) << 1
+            | (velocity.is_some() as u8) << 2
+            | (size.is_some() as u8) << 3
+            | (state.is_some() as u8) << 4;
+        self.u8(flags);
+        if let Some(pos) = pos {
+            self.vec2(*pos);
+        }
+        if let Some(velocity) = velocity {
+            self.vec2(*velocity);
+        }
+        if let Some(size) = size {
+            self.quantized(*size);
+        }
+        if let Some(state) = state {
+            self.player_state(state);
+        }
+    }
+
+    fn platform(&mut self, platform: &Platform) {
+        self.quantized(platform.size.0);
+        self.quantized(platform.size.1);
+        self.vec2(platform.pos);
+    }
+
+    fn player(&mut self, player: &Player) {
+        self.vec2(player.pos);
+        self.vec2(player.velocity);
+        self.quantized(player.size);
+        self.player_state(&player.state);
+    }
+
+    /// A player without quantization, for states which have to match bit for bit.
+    fn exact_player(&mut self, player: &Player) {
+        for v in [player.pos, player.velocity] {
+            self.exact(v.x);
+            self.exact(v.y);
+        }
+        self.exact(player.size);
+        self.player_state(&player.state);
+    }
+
+    fn player_state(&mut self, state: &PlayerState) {
+        match state {
+            PlayerState::WallBound(dir) => {
+                self.u8(0);
+                self.i8(*dir);
+            }
+            PlayerState::Grounded => self.u8(1),
+            PlayerState::Airborne => self.u8(2),
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    /// Return the decoded value only if all bytes were consumed.
+    fn finish<T>(self, value: T) -> Option<T> {
+        self.0.is_empty().then_some(value)
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (head, tail) = self.0.split_first_chunk()?;
+        self.0 = tail;
+        Some(*head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take().map(u8::from_le_bytes)
+    }
+
+    fn i8(&mut self) -> Option<i8> {
+        self.take().map(i8::from_le_bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn i16(&mut self) -> Option<i16> {
+        self.take().map(i16::from_le_bytes)
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        self.u32().map(|v| v as usize)
+    }
+
+    /// A float written by `Writer::quantized`, in steps of 1/32 within about ±1024.
+    fn quantized(&mut self) -> Option<f64> {
+        self.i16().map(dequantize)
+    }
+
+    fn exact(&mut self) -> Option<f64> {
+        self.u64().map(f64::from_bits)
+    }
+
+    fn vec2(&mut self) -> Option<Vec2> {
+        Some(Vec2::new(self.quantized()?, self.quantized()?))
+    }
+
+    fn len(&mut self) -> Option<usize> {
+        self.u16().map(|v| v as usize)
+    }
+
+    fn message(&mut self) -> Option<Message> {
+        Some(Message {
+            id: self.usize()?,
+            x: self.i8()?,
+            y: self.i8()?,
+            snapshot_ack: self.usize()?,
+            redundant: self.inputs()?,
+        })
+    }
+
+    fn inputs(&mut self) -> Option<Vec<(i8, i8)>> {
+        (0..self.len()?)
+            .map(|_| Some((self.i8()?, self.i8()?)))
+            .collect()
+    }
+
+    fn mode(&mut self) -> Option<Mode> {
+        match self.u8()? {
+            0 => Some(Mode::Authoritative),
+            1 => Some(Mode::Rollback),
+            2 => Some(Mode::Lockstep),
+            _ => None,
+        }
+    }
+
+    fn physics(&mut self) -> Option<Physics> {
+        match self.u8()? {
+            0 => Some(Physics::Float),
+            1 => Some(Physics::Fixed),
+            _ => None,
+        }
+    }
+
+    fn frame_inputs(&mut self) -> Option<Vec<Option<(i8, i8)>>> {
+        (0..self.len()?)
+            .map(|_| match self.u8()? {
+                0 => Some(None),
+                _ => Some(Some((self.i8()?, self.i8()?))),
+            })
+            .collect()
+    }
+
+    fn checksum(&mut self) -> Option<Option<(u64, u64)>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => Some(Some((self.u64()?, self.u64()?))),
+        }
+    }
+
+    fn client_packet(&mut self) -> Option<ClientPacket> {
+        match self.u8()? {
+            0 => Some(ClientPacket::Input(self.message()?)),
+            1 => Some(ClientPacket::Disconnect),
+            2 => Some(ClientPacket::LevelRequest),
+            3 => Some(ClientPacket::Connect {
+                protocol_version: self.u32()?,
+            }),
+            4 => Some(ClientPacket::Ping {
+                client_time_us: self.u64()?,
+            }),
+            5 => Some(ClientPacket::PeerInput {
+                frame: self.u64()?,
+                inputs: self.inputs()?,
+            }),
+            6 => Some(ClientPacket::LockstepInput {
+                frame: self.u64()?,
+                inputs: self.inputs()?,
+                next_frame: self.u64()?,
+                checksum: self.checksum()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn server_packet(&mut self) -> Option<ServerPacket> {
+        match self.u8()? {
+            0 => Some(ServerPacket::Welcome {
+                platforms: (0..self.len()?)
+                    .map(|_| self.platform())
+                    .collect::<Option<_>>()?,
+                level_hash: self.u64()?,
+                physics: self.physics()?,
+            }),
+            1 => Some(ServerPacket::Snapshot(self.response()?)),
+            2 => Some(ServerPacket::Accept {
+                player_idx: self.usize()?,
+                max_players: self.len()?,
+                mode: self.mode()?,
+            }),
+            3 => Some(ServerPacket::Reject(RejectReason::VersionMismatch {
+                server_version: self.u32()?,
+            })),
+            4 => Some(ServerPacket::Reject(RejectReason::ServerFull {
+                max_players: self.len()?,
+            })),
+            5 => Some(ServerPacket::Pong {
+                client_time_us: self.u64()?,
+                server_time_us: self.u64()?,
+            }),
+            6 => Some(ServerPacket::PeerInput {
+                player_idx: self.usize()?,
+                frame: self.u64()?,
+                inputs: self.inputs()?,
+            }),
+            7 => Some(ServerPacket::LockstepStart {
+                frame: self.u64()?,
+                players: (0..self.len()?)
+                    .map(|_| match self.u8()? {
+                        0 => Some(None),
+                        _ => self.exact_player().map(Some),
+                    })
+                    .collect::<Option<_>>()?,
+            }),
+            8 => Some(ServerPacket::LockstepInputs {
+                frame: self.u64()?,
+                inputs: (0..self.len()?)
+                    .map(|_| self.frame_inputs())
+                    .collect::<Option<_>>()?,
+                checksum: self.checksum()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn response(&mut self) -> Option<ServerResponse> {
+        Some(ServerResponse {
+            ack_id: self.usize()?,
+            player_hash: match self.u8()? {
+                0 => None,
+                _ => Some(self.u64()?),
+            },
+            level_hash: self.u64()?,
+            sequence: self.usize()?,
+            tick: self.u64()?,
+            time_us: self.u64()?,
+            input: self.input_status()?,
+            snapshot: self.snapshot()?,
+        })
+    }
+
+    fn input_status(&mut self) -> Option<InputStatus> {
+        Some(InputStatus {
+            depth: self.len()?,
+            target: self.len()?,
+            underruns: self.usize()?,
+            overruns: self.usize()?,
+        })
+    }
+
+    fn snapshot(&mut self) -> Option<Snapshot> {
+        match self.u8()? {
+            0 => {
+                let players = (0..self.len()?)
+                    .map(|_| match self.u8()? {
+                        0 => Some(None),
+                        _ => self.player().map(Some),
+                    })
+                    .collect::<Option<_>>()?;
+                Some(Snapshot::Full(players))
+            }
+            1 => Some(Snapshot::Delta {
+                baseline: self.usize()?,
+                len: self.len()?,
+                changes: (0..self.len()?)
+                    .map(|_| {
+                        Some(PlayerDelta {
+                            player_idx: self.len()?,
+                            change: self.player_change()?,
+                        })
+                    })
+                    .collect::<Option<_>>()?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn player_change(&mut self) -> Option<PlayerChange> {
+        let flags = self.u8()?;
+        if flags & 1 == 0 {
+            return Some(PlayerChange::Removed);
+        }
+
+        Some(PlayerChange::Changed {
+            pos: self.optional(flags & 1 << 1 != 0, Self::vec2)?,
+            velocity: self.optional(flags & 1 << 2 != 0, Self::vec2)?,
+            size: self.optional(flags & 1 << 3 != 0, Self::quantized)?,
+            state: self.optional(flags & 1 << 4 != 0, Self::player_state)?,
+        })
+    }
+
+    /// Read a value only if it is `present`.
+    fn optional<T>(
+        &mut self,
+        present: bool,
+        read: impl FnOnce(&mut Self) -> Option<T>,
+    ) -> Option<Option<T>> {
+        if present {
+            read(self).map(Some)
+        } else {
+            Some(None)
+        }
+    }
+
+    fn platform(&mut self) -> Option<Platform> {
+        Some(Platform {
+            size: (self.quantized()?, self.quantized()?),
+            pos: self.vec2()?,
+        })
+    }
+
+    fn player(&mut self) -> Option<Player> {
+        Some(Player {
+            pos: self.vec2()?,
+            velocity: self.vec2()?,
+            size: self.quantized()?,
+            state: self.player_state()?,
+        })
+    }
+
+    fn exact_player(&mut self) -> Option<Player> {
+        Some(Player {
+            pos: Vec2::new(self.exact()?, self.exact()?),
+            velocity: Vec2::new(self.exact()?, self.exact()?),
+            size: self.exact()?,
+            state: self.player_state()?,
+        })
+    }
+
+    fn player_state(&mut self) -> Option<PlayerState> {
+        match self.u8()? {
+            0 => Some(PlayerState::WallBound(self.i8()?)),
+            1 => Some(PlayerState::Grounded),
+            2 => Some(PlayerState::Airborne),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> ServerPacket {
+        ServerPacket::Snapshot(server_response())
+    }
+
+    fn server_response() -> ServerResponse {
+        let mut player = Player::new();
+        player.pos = Vec2::new(12.5, -3.25);
+        player.velocity = Vec2::new(-100., 42.03125);
+        player.state = PlayerState::WallBound(-1);
+
+        ServerResponse {
+            ack_id: 123_456,
+            player_hash: Some(player.state_hash()),
+            level_hash: Game::new().level_hash(),
+            sequence: 10,
+            tick: 1_234,
+            time_us: 20_566_000,
+            input: InputStatus {
+                depth: 3,
+                target: 2,
+                underruns: 17,
+                overruns: 1,
+            },
+            snapshot: Snapshot::Full(vec![Some(player), None, Some(Player::new())]),
+        }
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let packet = ClientPacket::Input(Message {
+            id: 99,
+            x: -1,
+            y: 1,
+            snapshot_ack: 3,
+            redundant: vec![(1, 0), (0, -1)],
+        });
+        let bytes = Binary.encode_client(&packet);
+        assert_eq!(bytes.len(), 17);
+        assert_eq!(Binary.decode_client(&bytes), Some(packet));
+
+        let others = [
+            ClientPacket::Disconnect,
+            ClientPacket::LevelRequest,
+            ClientPacket::Connect {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            ClientPacket::Ping {
+                client_time_us: 1_500_000,
+            },
+            ClientPacket::PeerInput {
+                frame: 420,
+                inputs: vec![(1, -1), (0, 0)],
+            },
+            ClientPacket::LockstepInput {
+                frame: 420,
+                inputs: vec![(0, -1)],
+                next_frame: 417,
+                checksum: Some((390, u64::MAX)),
+            },
+        ];
+        for packet in others {
+            let bytes = Binary.encode_client(&packet);
+            assert_eq!(Binary.decode_client(&bytes), Some(packet));
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let response = response();
+        let bytes = Binary.encode_server(&response);
+        assert_eq!(Binary.decode_server(&bytes), Some(response));
+    }
+
+    #[test]
+    fn welcome_round_trip() {
+        let game = Game::new();
+        let welcome = ServerPacket::Welcome {
+            platforms: game.platforms.clone(),
+            level_hash: game.level_hash(),
+            physics: Physics::Fixed,
+        };
+        let bytes = Binary.encode_server(&welcome);
+        assert_eq!(Binary.decode_server(&bytes), Some(welcome));
+    }
+
+    #[test]
+    fn lockstep_start_is_exact() {
+        let mut player = Player::new();
+        player.pos = Vec2::new(100.123_456_789, -0.1);
+        player.velocity = Vec2::new(1. / 3., 2e-9);
+        let start = ServerPacket::LockstepStart {
+            frame: 1234,
+            players: vec![None, Some(player)],
+        };
+        let bytes = Binary.encode_server(&start);
+        assert_eq!(Binary.decode_server(&bytes), Some(start));
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let packets = [
+            ServerPacket::Accept {
+                player_idx: 3,
+                max_players: MAX_PLAYERS,
+                mode: Mode::Rollback,
+            },
+            ServerPacket::Reject(RejectReason::VersionMismatch { server_version: 2 }),
+            ServerPacket::Reject(RejectReason::ServerFull { max_players: 6 }),
+            ServerPacket::Pong {
+                client_time_us: 1_500_000,
+                server_time_us: 20_566_000,
+            },
+            ServerPacket::PeerInput {
+                player_idx: 2,
+                frame: 420,
+                inputs: vec![(-1, 0)],
+            },
+            ServerPacket::LockstepInputs {
+                frame: 417,
+                inputs: vec![vec![Some((1, 0)), None], vec![Some((0, -1)), None]],
+                checksum: None,
+            },
+        ];
+        for packet in packets {
+            let bytes = Binary.encode_server(&packet);
+            assert_eq!(Binary.decode_server(&bytes), Some(packet));
+        }
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let mut response = server_response();
+        response.snapshot = Snapshot::Delta {
+            baseline: 9,
+            len: 3,
+            changes: vec![
+                PlayerDelta {
+                    player_idx: 0,
+                    change: PlayerChange::Removed,
+                },
+                PlayerDelta {
+                    player_idx: 2,
+                    change: PlayerChange::Changed {
+                        pos: Some(Vec2::new(1., 2.)),
+                        velocity: None,
+                        size: None,
+                        state: Some(PlayerState::Grounded),
+                    },
+                },
+            ],
+        };
+        let packet = ServerPacket::Snapshot(response);
+        let bytes = Binary.encode_server(&packet);
+        assert_eq!(Binary.decode_server(&bytes), Some(packet));
+    }
+
+    #[test]
+    fn smaller_than_json() {
+        let response = response();
+        let binary = Binary.encode_server(&response);
+        let json = super::super::Json.encode_server(&response);
+        assert!(binary.len() * 4 < json.len());
+    }
+
+    #[test]
+    fn quantization_rounds_and_clamps() {
+        assert_eq!(dequantize(quantize(1.01)), 1.);
+        assert_eq!(dequantize(quantize(1e9)), i16::MAX as f64 / VEC2_SCALE);
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_bytes() {
+        let bytes = Binary.encode_server(&response());
+        assert_eq!(Binary.decode_server(&bytes[..bytes.len() - 1]), None);
+
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(Binary.decode_server(&bytes), None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Simulation of bad network conditions.
+
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::math::Rng;
+
+/// Packets are dropped if the bandwidth cap would delay them more than this.
+const MAX_QUEUE_DELAY: Duration = Duration::from_secs(1);
+
+/// Extra delay given to a packet which is chosen to be reordered, letting later packets overtake it.
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// The simulated conditions of a network link.
+///
+/// The conditions apply to each direction separately, except for the ping, which is split evenly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conditions {
+    /// Simulated round-trip time. Each direction is delayed by half of this.
+    pub ping_ms: u64,
+    /// Random variation of the delay of each packet.
+    ///
+    /// For a uniform distribution this is the maximum variation,
+    /// and for a normal distribution it is the standard deviation.
+    pub jitter_ms: u64,
+    /// The distribution of the jitter.
+    pub jitter: Jitter,
+    /// Percentage of packets which are lost.
+    pub loss_percent: u32,
+    /// Percentage of packets which arrive twice.
+    pub duplicate_percent: u32,
+    /// Percentage of packets which are delayed enough to arrive after later packets.
+    ///
+    /// Other packets arrive in the order they were sent, even with jitter.
+    pub reorder_percent: u32,
+    /// Maximum bandwidth in kilobits per second, or 0 for no limit.
+    pub bandwidth_kbps: u64,
+}
+
+/// The distribution of the random variation in packet delay.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Jitter {
+    /// Delays vary evenly within the jitter.
+    Uniform,
+    /// Delays vary with a bell curve, mostly within the jitter but sometimes beyond it.
+    Normal,
+}
+
+impl Conditions {
+    /// A perfect link without any delay or loss.
+    pub const NONE: Self = Conditions {
+        ping_ms: 0,
+        jitter_ms: 0,
+        jitter: Jitter::Uniform,
+        loss_percent: 0,
+        duplicate_percent: 0,
+        reorder_percent: 0,
+        bandwidth_kbps: 0,
+    };
+}
+
+impl Display for Conditions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bandwidth = match self.bandwidth_kbps {
+            0 => String::from("unlimited"),
+            kbps => format!("{kbps} kbps"),
+        };
+        write!(
+            f,
+            "Ping: {:?}\nJitter: {:?} ({:?})\nLoss: {}%\nDuplicate: {}%\nReorder: {}%\nBandwidth: {}",
+            Duration::from_millis(self.ping_ms),
+            Duration::from_millis(self.jitter_ms),
+            self.jitter,
+            self.loss_percent,
+            self.duplicate_percent,
+            self.reorder_percent,
+            bandwidth,
+        )
+    }
+}
+
+/// The simulated state of one direction of a link.
+pub struct Link {
+    conditions: Conditions,
+    rng: Rng,
+    /// When the packets sent so far have been transmitted, used for the bandwidth cap.
+    busy_until: Instant,
+    /// The latest due time of the packets which are delivered in order.
+    last_due: Instant,
+}
+
+impl Link {
+    pub fn new(conditions: Conditions, rng: Rng) -> Self {
+        let now = Instant::now();
+        Self {
+            conditions,
+            rng,
+            busy_until: now,
+            last_due: now,
+        }
+    }
+
+    pub fn conditions(&self) -> Conditions {
+        self.conditions
+    }
+
+    pub fn set_conditions(&mut self, conditions: Conditions) {
+        self.conditions = conditions;
+    }
+
+    /// Decide what happens to a packet of `len` bytes sent at `now`.
+    ///
+    /// Returns the times at which copies of the packet should be delivered.
+    /// The result is empty if the packet is lost, and has two entries if it is duplicated.
+    pub fn schedule(&mut self, len: usize, now: Instant) -> Vec<Instant> {
+        let c = self.conditions;
+
+        if self.rng.chance(c.loss_percent as f64 / 100.) {
+            return Vec::new();
+        }
+
+        let mut sent = now;
+        if c.bandwidth_kbps > 0 {
+            let transmit =
+                Duration::from_secs_f64((len * 8) as f64 / (c.bandwidth_kbps * 1000) as f64);
+            sent = self.busy_until.max(now) + transmit;
+            if sent - now > MAX_QUEUE_DELAY {
+                return Vec::new();
+            }
+            self.busy_until = sent;
+        }
+
+        let mut due = sent + self.delay();
+        if self.rng.chance(c.reorder_percent as f64 / 100.) {
+            due += REORDER_DELAY;
+        } else {
+            due = due.max(self.last_due);
+            self.last_due = due;
+        }
+
+        let mut copies = vec![due];
+        if self.rng.chance(c.duplicate_percent as f64 / 100.) {
+            copies.push(sent + self.delay());
+        }
+        copies
+    }
+
+    /// One-way delay, including jitter.
+    fn delay(&mut self) -> Duration {
+        let c = self.conditions;
+        let jitter = c.jitter_ms as f64;
+        let offset = match c.jitter {
+            Jitter::Uniform => (self.rng.f64() * 2. - 1.) * jitter,
+            Jitter::Normal => self.rng.normal() * jitter,
+        };
+        let ms = (c.ping_ms as f64 / 2. + offset).max(0.);
+        Duration::from_secs_f64(ms / 1000.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(conditions: Conditions) -> Link {
+        Link::new(conditions, Rng::new(7))
+    }
+
+    #[test]
+    fn perfect_link_delivers_immediately() {
+        let mut link = link(Conditions::NONE);
+        let now = Instant::now();
+        assert_eq!(link.schedule(100, now), vec![now]);
+    }
+
+    #[test]
+    fn ping_delays_by_half() {
+        let mut link = link(Conditions {
+            ping_ms: 100,
+            ..Conditions::NONE
+        });
+        let now = Instant::now();
+        assert_eq!(
+            link.schedule(100, now),
+            vec![now + Duration::from_millis(50)]
+        );
+    }
+
+    #[test]
+    fn loss_and_duplication() {
+        let mut lossy = link(Conditions {
+            loss_percent: 100,
+            ..Conditions::NONE
+        });
+        assert!(lossy.schedule(100, Instant::now()).is_empty());
+
+        let mut duplicating = link(Conditions {
+            duplicate_percent: 100,
+            ..Conditions::NONE
+        });
+        assert_eq!(duplicating.schedule(100, Instant::now()).len(), 2);
+    }
+
+    #[test]
+    fn jitter_keeps_order() {
+        let mut link = link(Conditions {
+            ping_ms: 100,
+            jitter_ms: 40,
+            jitter: Jitter::Normal,
+            ..Conditions::NONE
+        });
+        let now = Instant::now();
+        let dues: Vec<_> = (0..100)
+            .map(|i| link.schedule(10, now + Duration::from_millis(i))[0])
+            .collect();
+        assert!(dues.is_sorted());
+    }
+
+    #[test]
+    fn reordered_packets_are_overtaken() {
+        let mut link = link(Conditions {
+            reorder_percent: 100,
+            ..Conditions::NONE
+        });
+        let now = Instant::now();
+        let first = link.schedule(10, now)[0];
+        link.set_conditions(Conditions::NONE);
+        let second = link.schedule(10, now + Duration::from_millis(1))[0];
+        assert!(second < first);
+    }
+
+    #[test]
+    fn bandwidth_queues_and_drops() {
+        // 8 kbps is 1000 bytes per second
+        let mut link = link(Conditions {
+            bandwidth_kbps: 8,
+            ..Conditions::NONE
+        });
+        let now = Instant::now();
+        assert_eq!(
+            link.schedule(500, now),
+            vec![now + Duration::from_millis(500)]
+        );
+        assert_eq!(link.schedule(500, now), vec![now + Duration::from_secs(1)]);
+        assert!(link.schedule(500, now).is_empty());
+    }
+}
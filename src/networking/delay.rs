@@ -0,0 +1,180 @@
+//! Queues for releasing delayed packets at their due time.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+/// Items ordered by their due time.
+///
+/// Items due at the same time are released in the order they were pushed.
+pub struct Schedule<T> {
+    heap: BinaryHeap<Reverse<Entry<T>>>,
+    next_seq: u64,
+}
+
+struct Entry<T> {
+    due: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+impl<T> Default for Schedule<T> {
+    fn default() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl<T> Schedule<T> {
+    pub fn push(&mut self, due: Instant, item: T) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.heap.push(Reverse(Entry { due, seq, item }));
+    }
+
+    /// The due time of the next item, if any.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse(e)| e.due)
+    }
+
+    /// Remove the next item, if it is due at `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.heap.pop().map(|Reverse(e)| e.item)
+    }
+}
+
+/// A `Schedule` served by a single background thread, which releases each item when it is due.
+///
+/// The thread stops when the queue is dropped.
+pub struct DelayQueue<T> {
+    shared: Arc<(Mutex<Shared<T>>, Condvar)>,
+}
+
+struct Shared<T> {
+    schedule: Schedule<T>,
+    closed: bool,
+}
+
+impl<T: Send + 'static> DelayQueue<T> {
+    /// Start the background thread, which calls `release` with each item when it is due.
+    pub fn spawn(mut release: impl FnMut(T) + Send + 'static) -> Self {
+        let shared = Arc::new((
+            Mutex::new(Shared {
+                schedule: Schedule::default(),
+                closed: false,
+            }),
+            Condvar::new(),
+        ));
+
+        let thread_shared = Arc::clone(&shared);
+        thread::spawn(move || {
+            let (lock, condvar) = &*thread_shared;
+            let mut guard = lock.lock().unwrap();
+            while !guard.closed {
+                let now = Instant::now();
+                if let Some(item) = guard.schedule.pop_due(now) {
+                    drop(guard);
+                    release(item);
+                    guard = lock.lock().unwrap();
+                    continue;
+                }
+
+                guard = match guard.schedule.next_due() {
+                    Some(due) => {
+                        condvar
+                            .wait_timeout(guard, due.saturating_duration_since(now))
+                            .unwrap()
+                            .0
+                    }
+                    None => condvar.wait(guard).unwrap(),
+                };
+            }
+        });
+
+        Self { shared }
+    }
+
+    /// Schedule an item to be released at `due`.
+    pub fn push(&self, due: Instant, item: T) {
+        let (lock, condvar) = &*self.shared;
+        lock.lock().unwrap().schedule.push(due, item);
+        condvar.notify_one();
+    }
+}
+
+impl<T> Drop for DelayQueue<T> {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.shared;
+        lock.lock().unwrap().closed = true;
+        condvar.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn schedule_orders_by_due_then_insertion() {
+        let now = Instant::now();
+        let later = now + Duration::from_millis(10);
+
+        let mut schedule = Schedule::default();
+        schedule.push(later, 'c');
+        schedule.push(now, 'a');
+        schedule.push(now, 'b');
+
+        assert_eq!(schedule.pop_due(now), Some('a'));
+        assert_eq!(schedule.pop_due(now), Some('b'));
+        assert_eq!(schedule.pop_due(now), None);
+        assert_eq!(schedule.next_due(), Some(later));
+        assert_eq!(schedule.pop_due(later), Some('c'));
+    }
+
+    #[test]
+    fn queue_releases_many_items_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let queue = DelayQueue::spawn(move |i| tx.send(i).unwrap());
+
+        let start = Instant::now();
+        let due = start + Duration::from_millis(20);
+        for i in 0..10_000 {
+            queue.push(due, i);
+        }
+
+        let received: Vec<i32> = rx.iter().take(10_000).collect();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(received.iter().copied().eq(0..10_000));
+    }
+}
@@ -5,10 +5,20 @@ use sdl2::{
     rect::{Point, Rect},
 };
 
-use crate::{math::Vec2, model::*, sys};
+use crate::{
+    math::Vec2,
+    model::*,
+    net_graph::{self, NetGraph},
+    sys,
+};
 
 const BG: Color = Color::WHITE;
 const PLATFORM: Color = Color::BLACK;
+const GRAPH_BG: Color = Color::RGB(230, 230, 230);
+const GRAPH_LINE: Color = Color::RGB(0, 90, 200);
+
+/// The height of each plot in the net graph.
+const PLOT_HEIGHT: i32 = 8;
 
 const PLAYER_COLORS: &[Color] = &[
     Color::RED,
@@ -35,6 +45,9 @@ pub fn game(game: &Game, canvas: &mut sdl2::render::WindowCanvas) {
     }
 
     for (i, player) in game.players.iter().enumerate() {
+        let Some(player) = player else {
+            continue;
+        };
         assert!(i < PLAYER_COLORS.len(), "Not enough colors :(");
 
         canvas.set_draw_color(PLAYER_COLORS[i]);
@@ -52,17 +65,62 @@ pub fn settings<'a, L: IntoIterator<Item = &'a str>>(
     font: &sdl2::ttf::Font,
     lines: L,
 ) {
-    for (i, line) in lines.into_iter().enumerate() {
-        let surface = font.render(line).blended(Color::BLACK).unwrap();
+    let mut y = 4;
+    for line in lines {
+        y += text(sdl, font, line, 20, y) as i32;
+    }
+}
+
+/// Render the net graph along the right edge of the window, in place of the text from `settings`.
+///
+/// Each measurement gets a label with its newest value, and a plot of its history
+/// scaled to its largest recent value.
+pub fn net_graph(sdl: &mut sys::SdlContext, font: &sdl2::ttf::Font, graph: &NetGraph) {
+    let (width, _) = sdl.canvas.logical_size();
+    let left = width as i32 - net_graph::HISTORY_LEN as i32 - 4;
 
-        let texture = sdl
-            .texture_creator
-            .create_texture_from_surface(&surface)
-            .unwrap();
+    let mut top = 2;
+    for series in graph.series() {
+        top += text(sdl, font, &series.label(), left, top) as i32;
 
-        let sdl2::render::TextureQuery { width, height, .. } = texture.query();
+        sdl.canvas.set_draw_color(GRAPH_BG);
+        let area = Rect::new(left, top, net_graph::HISTORY_LEN as u32, PLOT_HEIGHT as u32);
+        let _ = sdl.canvas.fill_rect(area);
 
-        let target = Rect::new(20, 4 + (height * i as u32) as i32, width, height);
-        let _ = sdl.canvas.copy(&texture, None, Some(target));
+        let max = series.max();
+        let bottom = top + PLOT_HEIGHT - 1;
+        let points: Vec<_> = series
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let height = if max > 0. {
+                    value / max * (PLOT_HEIGHT - 1) as f64
+                } else {
+                    0.
+                };
+                Point::new(left + i as i32, bottom - height.round() as i32)
+            })
+            .collect();
+        sdl.canvas.set_draw_color(GRAPH_LINE);
+        let _ = sdl.canvas.draw_lines(&points[..]);
+
+        top += PLOT_HEIGHT + 1;
     }
 }
+
+/// Render one line of text with its top left corner at (`x`, `y`), and return its height.
+fn text(sdl: &mut sys::SdlContext, font: &sdl2::ttf::Font, line: &str, x: i32, y: i32) -> u32 {
+    let surface = font.render(line).blended(Color::BLACK).unwrap();
+
+    let texture = sdl
+        .texture_creator
+        .create_texture_from_surface(&surface)
+        .unwrap();
+
+    let sdl2::render::TextureQuery { width, height, .. } = texture.query();
+
+    let target = Rect::new(x, y, width, height);
+    let _ = sdl.canvas.copy(&texture, None, Some(target));
+    height
+}
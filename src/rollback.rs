@@ -0,0 +1,415 @@
+//! Rollback netcode, where every peer simulates all players from their inputs.
+//!
+//! Inputs which have not arrived yet are predicted by repeating the player's last known input.
+//! When the actual input arrives and differs from the prediction,
+//! the game is rolled back to the state before that frame and simulated again.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{client, model::*};
+
+/// Inputs may arrive up to this many frames late. Older frames are final.
+pub const MAX_ROLLBACK: u64 = 30;
+
+/// Frames of history kept, so that final states from the server can still be compared when they arrive.
+const HISTORY_LEN: usize = 2 * MAX_ROLLBACK as usize;
+
+/// States from the server which differ from the local ones by more than this are corrected.
+const TOLERANCE: f64 = 0.1;
+
+/// Counts of what the rollback has done, for debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The number of times the game was rolled back.
+    pub rollbacks: usize,
+    /// The number of frames simulated again because of rollbacks.
+    pub resimulated: usize,
+    /// The most frames rolled back at once.
+    pub max_depth: u64,
+    /// Inputs which arrived too late to roll back to.
+    pub late: usize,
+    /// The number of times a state from the server differed from the local one.
+    pub corrections: usize,
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rollbacks: {} ({} frames, at most {}), {} late inputs, {} corrections",
+            self.rollbacks, self.resimulated, self.max_depth, self.late, self.corrections
+        )
+    }
+}
+
+/// A game simulated one frame at a time from the inputs of all players, which can be rolled back.
+pub struct Rollback {
+    /// The next frame to simulate.
+    frame: u64,
+    /// The state before `frame`.
+    game: Game,
+    /// The most recent frames, oldest first, ending with the frame before `frame`.
+    history: VecDeque<Frame>,
+    /// The inputs received from each player, by frame.
+    inputs: Vec<BTreeMap<u64, (i8, i8)>>,
+    /// The oldest frame which was simulated with a different input than the one now known.
+    dirty: Option<u64>,
+    stats: Stats,
+}
+
+/// One simulated frame.
+struct Frame {
+    number: u64,
+    /// The players before the frame was simulated.
+    players: Vec<Option<Player>>,
+    /// The input used for each player slot, received or predicted.
+    inputs: Vec<(i8, i8)>,
+}
+
+impl Rollback {
+    /// Start simulating from `game`, as the state before `frame`.
+    pub fn new(game: Game, frame: u64) -> Self {
+        Self {
+            frame,
+            game,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            inputs: Vec::new(),
+            dirty: None,
+            stats: Stats::default(),
+        }
+    }
+
+    /// The next frame to simulate.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The state before the next frame.
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Add the inputs of a player, newest first, where the first one is for `frame`.
+    ///
+    /// Inputs further ahead than the window are ignored, since the peer's clock must be off.
+    /// Returns `false` if none of the inputs were new and in time.
+    pub fn add_inputs(&mut self, player_idx: usize, frame: u64, inputs: &[(i8, i8)]) -> bool {
+        if player_idx >= self.inputs.len() {
+            self.inputs.resize_with(player_idx + 1, BTreeMap::new);
+        }
+        let oldest = self.frame.saturating_sub(MAX_ROLLBACK);
+
+        let mut added = false;
+        for (number, &input) in (0..=frame).rev().zip(inputs) {
+            if number > self.frame + MAX_ROLLBACK {
+                continue;
+            }
+            if number < oldest {
+                self.stats.late += 1;
+                break;
+            }
+            if self.inputs[player_idx].insert(number, input).is_some() {
+                continue;
+            }
+            added = true;
+
+            // a player who was in the frame, but was simulated with another input
+            let mispredicted = self.history_frame(number).is_some_and(|f| {
+                f.players.get(player_idx).is_some_and(Option::is_some)
+                    && f.inputs.get(player_idx) != Some(&input)
+            });
+            if mispredicted {
+                self.dirty = Some(self.dirty.map_or(number, |d| d.min(number)));
+            }
+        }
+        added
+    }
+
+    /// The inputs of a player up to its newest one, newest first, at most `count` of them.
+    ///
+    /// Returns the frame of the newest input along with the inputs.
+    pub fn recent_inputs(&self, player_idx: usize, count: usize) -> Option<(u64, Vec<(i8, i8)>)> {
+        let inputs = self.inputs.get(player_idx)?;
+        let (&newest, _) = inputs.last_key_value()?;
+        let recent = (0..=newest)
+            .rev()
+            .map_while(|number| inputs.get(&number).copied())
+            .take(count)
+            .collect();
+        Some((newest, recent))
+    }
+
+    /// Simulate the next frame, after rolling back to correct any mispredicted frames.
+    pub fn advance(&mut self) {
+        if let Some(dirty) = self.dirty.take() {
+            self.roll_back(dirty);
+        }
+        self.step();
+    }
+
+    /// The state before the newest final frame, which can no longer change.
+    pub fn finalized(&self) -> Option<(u64, &[Option<Player>])> {
+        let number = self.frame.checked_sub(MAX_ROLLBACK)?;
+        let frame = self.history_frame(number)?;
+        Some((number, &frame.players))
+    }
+
+    /// Compare the state before `frame` with one from the server, and correct it if they differ.
+    ///
+    /// A state newer than any simulated frame replaces the game, which is then simulated onwards from it.
+    pub fn correct(&mut self, frame: u64, players: Vec<Option<Player>>) {
+        if frame >= self.frame {
+            self.game.players = players;
+            self.frame = frame;
+            self.history.clear();
+            self.dirty = None;
+            self.stats.corrections += 1;
+            return;
+        }
+
+        let Some(i) = self.history.iter().position(|f| f.number == frame) else {
+            return;
+        };
+        if !same_players(&self.history[i].players, &players) {
+            self.history[i].players = players;
+            self.dirty = Some(self.dirty.map_or(frame, |d| d.min(frame)));
+            self.stats.corrections += 1;
+        }
+    }
+
+    /// Add a player in slot `player_idx`, as if they had been there for all frames that can still be rolled back.
+    pub fn add_player(&mut self, player_idx: usize) {
+        let states = self.history.iter_mut().map(|f| &mut f.players);
+        for players in states.chain([&mut self.game.players]) {
+            if player_idx >= players.len() {
+                players.resize_with(player_idx + 1, || None);
+            }
+            players[player_idx] = Some(Player::new());
+        }
+    }
+
+    /// Remove a player from all frames that can still be rolled back.
+    pub fn remove_player(&mut self, player_idx: usize) {
+        let states = self.history.iter_mut().map(|f| &mut f.players);
+        for players in states.chain([&mut self.game.players]) {
+            if let Some(slot) = players.get_mut(player_idx) {
+                *slot = None;
+            }
+        }
+        if let Some(inputs) = self.inputs.get_mut(player_idx) {
+            inputs.clear();
+        }
+    }
+
+    fn history_frame(&self, number: u64) -> Option<&Frame> {
+        let oldest = self.history.front()?.number;
+        self.history.get(number.checked_sub(oldest)? as usize)
+    }
+
+    /// Restore the state before `frame`, and simulate up to the current frame again.
+    fn roll_back(&mut self, frame: u64) {
+        let Some(oldest) = self.history.front().map(|f| f.number) else {
+            return;
+        };
+        let Some(i) = frame.checked_sub(oldest).map(|i| i as usize) else {
+            return;
+        };
+        let Some(restored) = self.history.get(i) else {
+            return;
+        };
+
+        let target = self.frame;
+        self.game.players = restored.players.clone();
+        self.history.truncate(i);
+        self.frame = frame;
+        while self.frame < target {
+            self.step();
+        }
+
+        let depth = target - frame;
+        self.stats.rollbacks += 1;
+        self.stats.resimulated += depth as usize;
+        self.stats.max_depth = self.stats.max_depth.max(depth);
+    }
+
+    /// Simulate one frame with the received or predicted inputs of all players.
+    fn step(&mut self) {
+        let inputs: Vec<_> = (0..self.game.players.len())
+            .map(|i| self.input(i, self.frame))
+            .collect();
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(Frame {
+            number: self.frame,
+            players: self.game.players.clone(),
+            inputs: inputs.clone(),
+        });
+
+        for (player_idx, input) in inputs.into_iter().enumerate() {
+            self.game
+                .player_physics(player_idx, input, client::DELTA_TIME);
+        }
+        self.frame += 1;
+
+        // keep the newest input before the history, which predicts the inputs after it
+        let oldest = self.history.front().map_or(self.frame, |f| f.number);
+        for inputs in &mut self.inputs {
+            while inputs.range(..oldest).nth(1).is_some() {
+                inputs.pop_first();
+            }
+        }
+    }
+
+    /// The received input of a player, or the last known one if it has not arrived.
+    fn input(&self, player_idx: usize, frame: u64) -> (i8, i8) {
+        self.inputs
+            .get(player_idx)
+            .and_then(|inputs| inputs.range(..=frame).next_back())
+            .map_or((0, 0), |(_, &input)| input)
+    }
+}
+
+fn same_players(a: &[Option<Player>], b: &[Option<Player>]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|pair| match pair {
+            (Some(a), Some(b)) => a.close_to(b, TOLERANCE),
+            (None, None) => true,
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(players: usize) -> Game {
+        let mut game = Game::new();
+        for _ in 0..players {
+            game.add_player();
+        }
+        game
+    }
+
+    fn pos(rollback: &Rollback, player_idx: usize) -> crate::math::Vec2 {
+        rollback.game().players[player_idx].as_ref().unwrap().pos
+    }
+
+    #[test]
+    fn late_inputs_roll_back_to_the_same_result() {
+        let mut on_time = Rollback::new(game(2), 0);
+        let mut late = Rollback::new(game(2), 0);
+        for frame in 0..20 {
+            let input = if frame < 5 { (0, 0) } else { (1, 0) };
+            on_time.add_inputs(1, frame, &[input]);
+            on_time.advance();
+            late.advance();
+        }
+
+        // the inputs of player 1 arrive all at once, newest first
+        let inputs: Vec<_> = (0..20)
+            .rev()
+            .map(|f| if f < 5 { (0, 0) } else { (1, 0) })
+            .collect();
+        assert!(late.add_inputs(1, 19, &inputs));
+        late.advance();
+        on_time.advance();
+
+        assert_eq!(late.game(), on_time.game());
+        let stats = late.stats();
+        assert_eq!(
+            (stats.rollbacks, stats.resimulated, stats.max_depth),
+            (1, 15, 15)
+        );
+        assert!(pos(&late, 1).x > pos(&late, 0).x);
+    }
+
+    #[test]
+    fn correct_predictions_do_not_roll_back() {
+        let mut rollback = Rollback::new(game(1), 0);
+        rollback.add_inputs(0, 0, &[(1, 0)]);
+        for _ in 0..10 {
+            rollback.advance();
+        }
+        assert!(rollback.add_inputs(0, 9, &[(1, 0), (1, 0)]));
+        assert!(
+            !rollback.add_inputs(0, 9, &[(1, 0)]),
+            "duplicates are ignored"
+        );
+        rollback.advance();
+        assert_eq!(rollback.stats().rollbacks, 0);
+    }
+
+    #[test]
+    fn inputs_older_than_the_window_are_late() {
+        let mut rollback = Rollback::new(game(1), 0);
+        for _ in 0..MAX_ROLLBACK + 5 {
+            rollback.advance();
+        }
+        assert!(!rollback.add_inputs(0, 2, &[(1, 0)]));
+        assert_eq!(rollback.stats().late, 1);
+        assert_eq!(rollback.finalized().map(|(frame, _)| frame), Some(5));
+    }
+
+    #[test]
+    fn recent_inputs_are_contiguous_and_newest_first() {
+        let mut rollback = Rollback::new(game(1), 0);
+        rollback.add_inputs(0, 3, &[(1, 0), (0, -1)]);
+        rollback.add_inputs(0, 0, &[(-1, 0)]);
+        assert_eq!(
+            rollback.recent_inputs(0, 8),
+            Some((3, vec![(1, 0), (0, -1)]))
+        );
+        assert_eq!(rollback.recent_inputs(1, 8), None);
+    }
+
+    #[test]
+    fn server_states_correct_diverged_peers() {
+        let mut server = Rollback::new(game(1), 0);
+        let mut peer = Rollback::new(game(1), 0);
+        server.add_inputs(0, 0, &[(1, 0)]);
+        for _ in 0..MAX_ROLLBACK + 10 {
+            server.advance();
+            peer.advance();
+        }
+        // the peer only gets the inputs which are still in time, so the earlier frames diverged
+        let inputs = [(1, 0); MAX_ROLLBACK as usize + 10];
+        peer.add_inputs(0, MAX_ROLLBACK + 9, &inputs);
+
+        let (frame, players) = server.finalized().unwrap();
+        peer.correct(frame, players.to_vec());
+        peer.advance();
+        server.advance();
+        assert_eq!(peer.stats().corrections, 1);
+        assert_eq!(peer.game(), server.game());
+
+        // a peer which is behind starts over from the state
+        let mut behind = Rollback::new(game(1), 0);
+        let (frame, players) = server.finalized().unwrap();
+        behind.correct(frame, players.to_vec());
+        assert_eq!(behind.frame(), frame);
+    }
+
+    #[test]
+    fn players_join_all_frames_that_can_be_rolled_back() {
+        let mut rollback = Rollback::new(game(1), 0);
+        for _ in 0..5 {
+            rollback.advance();
+        }
+        rollback.add_player(1);
+        rollback.add_inputs(1, 0, &[(1, 0)]);
+        rollback.advance();
+
+        assert_eq!(rollback.stats().rollbacks, 1);
+        assert!(rollback.history.iter().all(|f| f.players[1].is_some()));
+
+        rollback.remove_player(0);
+        assert!(rollback.game().players[0].is_none());
+        assert_eq!(rollback.recent_inputs(0, 1), None);
+    }
+}
@@ -1,71 +1,171 @@
 //! The server's game loop.
 
-use std::{error::Error, io, time::Duration};
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use sdl2::EventPump;
 
-use crate::{model::*, networking, render, sys};
+use crate::{
+    client,
+    config::Config,
+    input_buffer::InputBuffer,
+    lockstep::{self, Lockstep},
+    model::*,
+    networking, render,
+    rollback::Rollback,
+    snapshot, sys,
+};
 
-pub const HOST: std::net::Ipv4Addr = std::net::Ipv4Addr::new(127, 0, 0, 1);
-pub const PORT: u16 = 7878;
+/// Snapshots broadcast per second, unless configured.
+pub const DEFAULT_TICKRATE: usize = 4;
 
-const DEFAULT_TICKRATE: usize = 4;
-const FRAME_TIME: Duration = Duration::from_millis(200);
+/// The fixed time step of the server simulation, independent of the tick rate used for broadcasting.
+const SIMULATION_TIME: Duration = Duration::from_nanos(16_666_666);
 
-/// Run the server.
+/// Simulation ticks run at most to catch up after a stall, such as a slow frame or a suspended process.
+/// Any time beyond this is dropped, so that catching up can not spiral.
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
+/// Time without any packets from a client before it is considered disconnected, unless configured.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Step used when adjusting the simulated ping of a client.
+const PING_INTERVAL: u64 = 50;
+
+/// Time between status messages when running headless.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Inputs buffered per client before they are simulated, unless configured.
+pub const DEFAULT_INPUT_BUFFER: usize = 2;
+
+/// Run the server on the configured address.
+///
+/// Without a window, the server runs headless: the tick rate stays fixed, and the status is logged to stdout.
 pub fn run(
-    mut sdl: sys::SdlContext,
-    font: &sdl2::ttf::Font,
-    shared: Game,
+    mut window: Option<(sys::SdlContext, &sdl2::ttf::Font)>,
+    codec: &dyn networking::Codec,
+    mut shared: Game,
+    config: &Config,
 ) -> Result<(), Box<dyn Error>> {
+    let mut tickrate = config.server.tickrate;
+    shared.physics = config.server.physics;
+    let rollback = (config.server.mode == Mode::Rollback).then(|| Rollback::new(shared.clone(), 0));
+    let lockstep = (config.server.mode == Mode::Lockstep).then(|| Lockstep::new(shared.clone(), 0));
     let mut state = State {
-        last_ack: Vec::new(),
         clients: Vec::new(),
+        timeout: config.server.timeout(),
+        max_players: config.server.max_players,
+        input_buffer: config.server.input_buffer,
+        sequence: 0,
+        tick: 0,
+        started: Instant::now(),
+        history: snapshot::History::default(),
+        rollback,
+        lockstep,
+        outbox: Vec::new(),
+        departed: Vec::new(),
         shared,
     };
 
-    let mut tickrate = DEFAULT_TICKRATE;
+    // the player whose simulated network conditions are edited, or `None` for the default conditions
+    let mut selected = None;
 
-    let mut server = networking::Server::bind(HOST, PORT)?;
+    let mut server = networking::Server::bind(config.addr())?;
 
-    let mut ticker = sys::ticker(FRAME_TIME);
+    let ticker = sys::ticker(SIMULATION_TIME);
+
+    let mut last_frame = Instant::now();
+    let mut simulation_lag = Duration::ZERO;
+    let mut broadcast_lag = Duration::ZERO;
+    let mut last_log = Instant::now();
 
     let mut running = true;
     while running {
         let tick = ticker.start();
 
-        handle_server_inputs(&mut sdl.events, &mut running, &mut tickrate);
-        let frame_time = Duration::from_secs_f64((tickrate as f64).recip());
-        ticker = sys::ticker(frame_time);
+        if selected.is_some_and(|i| state.addr(i).is_none()) {
+            selected = None;
+        }
+        if let Some((sdl, _)) = &mut window {
+            handle_server_inputs(
+                &mut sdl.events,
+                &mut running,
+                &mut tickrate,
+                &mut selected,
+                &state,
+                &mut server,
+            );
+        }
+        let broadcast_time = Duration::from_secs_f64((tickrate as f64).recip());
 
         while let Ok((data, origin)) = server.recv() {
-            let mut player_idx = state.clients.len();
-            for (i, client) in state.clients.iter().enumerate() {
-                if *client == origin {
-                    player_idx = i;
-                }
+            let Some(packet) = codec.decode_client(&data) else {
+                continue;
+            };
+            for reply in state.handle_packet(origin, packet) {
+                let _ = server.send(&codec.encode_server(&reply), origin);
             }
-
-            if player_idx >= state.clients.len() {
-                state.clients.push(origin);
-                state.last_ack.push(0);
-                state.shared.players.push(Player::new());
+            // only accepted clients keep their links, so rejected and stray packets leave nothing behind
+            if state.find(origin).is_none() {
+                server.forget(origin);
             }
+        }
 
-            let message: Message = serde_json::from_slice(data).unwrap();
-            let movement = (message.x, message.y);
+        state.drop_timed_out();
+        for addr in state.departed.drain(..) {
+            server.forget(addr);
+        }
 
-            state.last_ack[player_idx] = message.id;
-            state
-                .shared
-                .player_physics(player_idx, movement, crate::client::DELTA_TIME);
+        let elapsed = last_frame.elapsed();
+        last_frame = Instant::now();
+        simulation_lag = (simulation_lag + elapsed).min(SIMULATION_TIME * MAX_CATCH_UP_TICKS);
+        broadcast_lag = (broadcast_lag + elapsed).min(broadcast_time * MAX_CATCH_UP_TICKS);
+
+        while simulation_lag >= SIMULATION_TIME {
+            state.simulate();
+            simulation_lag -= SIMULATION_TIME;
+        }
+
+        state.send_confirmed();
+        for (addr, packet) in state.outbox.drain(..) {
+            let _ = server.send(&codec.encode_server(&packet), addr);
+        }
+
+        if broadcast_lag >= broadcast_time {
+            broadcast(&mut state, &mut server, codec)?;
+            broadcast_lag = (broadcast_lag - broadcast_time).min(broadcast_time);
         }
 
-        broadcast(&state, &server)?;
-        render::game(&state.shared, &mut sdl.canvas);
-        let text = format!("Server ticks per second: {}", tickrate);
-        render::settings(&mut sdl, font, Some(text.as_str()));
-        sdl.canvas.present();
+        match &mut window {
+            Some((sdl, font)) => {
+                render::game(&state.shared, &mut sdl.canvas);
+                let (target, conditions) = match selected.and_then(|i| state.addr(i)) {
+                    Some(addr) => (
+                        format!("player {}", selected.unwrap_or_default()),
+                        server.conditions(addr),
+                    ),
+                    None => (String::from("default"), server.default_conditions()),
+                };
+                let text = format!(
+                    "Mode: {}\nServer ticks per second: {}\nConditions for {} (Tab to switch):\n{}",
+                    state.mode(),
+                    tickrate,
+                    target,
+                    conditions
+                );
+                render::settings(sdl, font, text.lines());
+                sdl.canvas.present();
+            }
+            None if last_log.elapsed() >= LOG_INTERVAL => {
+                last_log = Instant::now();
+                println!("{}", state.status(tickrate));
+            }
+            None => (),
+        }
 
         tick.wait();
     }
@@ -74,28 +174,420 @@ pub fn run(
 }
 
 struct State {
-    last_ack: Vec<usize>,
-    clients: Vec<std::net::SocketAddr>,
+    /// Connected clients. The index of a client is the ID of its player.
+    clients: Vec<Option<Connection>>,
+    timeout: Duration,
+    max_players: usize,
+    /// The target depth of the input buffer of each client.
+    input_buffer: usize,
+    /// The sequence number of the last broadcast snapshot.
+    sequence: usize,
+    /// The number of simulation ticks run so far.
+    tick: u64,
+    /// When the server started, the origin of the server time in snapshots.
+    started: Instant,
+    /// Recently broadcast snapshots, used as baselines for delta compression.
+    history: snapshot::History,
+    /// The simulation in `Mode::Rollback`, which `shared` follows.
+    rollback: Option<Rollback>,
+    /// The simulation in `Mode::Lockstep`, which `shared` follows.
+    lockstep: Option<Lockstep>,
+    /// Packets for clients other than the one being handled, sent after all packets have been handled.
+    outbox: Vec<(SocketAddr, ServerPacket)>,
+    /// Addresses of clients which left, whose links the network layer can forget.
+    departed: Vec<SocketAddr>,
     shared: Game,
 }
 
-fn broadcast(state: &State, server: &networking::Server) -> io::Result<()> {
-    for (i, addr) in state.clients.iter().enumerate() {
+/// Server-side bookkeeping for one connected client.
+struct Connection {
+    addr: SocketAddr,
+    last_ack: usize,
+    snapshot_ack: usize,
+    last_seen: Instant,
+    inputs: InputBuffer,
+    lockstep: LockstepProgress,
+}
+
+/// How far a client has come in `Mode::Lockstep`.
+#[derive(Default)]
+struct LockstepProgress {
+    /// The next frame the client needs the inputs of, or `None` before it has started.
+    next_frame: Option<u64>,
+    /// The newest frame whose checksum the client has sent.
+    checked: u64,
+    /// The number of checksums from the client which differed from the server's.
+    desyncs: usize,
+}
+
+impl State {
+    /// Handle one packet from a client.
+    ///
+    /// Returns the packets to send back to the client.
+    /// Packets other than `ClientPacket::Connect` from unknown clients are ignored.
+    fn handle_packet(&mut self, origin: SocketAddr, packet: ClientPacket) -> Vec<ServerPacket> {
+        let existing = self.find(origin);
+
+        if let ClientPacket::Connect { protocol_version } = packet {
+            return match self.accept(origin, existing, protocol_version) {
+                Ok(player_idx) => vec![
+                    ServerPacket::Accept {
+                        player_idx,
+                        max_players: self.max_players,
+                        mode: self.mode(),
+                    },
+                    self.welcome(),
+                ],
+                Err(reason) => vec![ServerPacket::Reject(reason)],
+            };
+        }
+
+        let Some(player_idx) = existing else {
+            return Vec::new();
+        };
+        let Some(connection) = &mut self.clients[player_idx] else {
+            return Vec::new();
+        };
+        connection.last_seen = Instant::now();
+
+        match packet {
+            ClientPacket::Connect { .. } => Vec::new(),
+            ClientPacket::Input(message) => {
+                connection.snapshot_ack = connection.snapshot_ack.max(message.snapshot_ack);
+                connection.inputs.push(&message);
+                Vec::new()
+            }
+            ClientPacket::Disconnect => {
+                self.disconnect(player_idx);
+                Vec::new()
+            }
+            ClientPacket::LevelRequest => vec![self.welcome()],
+            ClientPacket::Ping { client_time_us } => vec![ServerPacket::Pong {
+                client_time_us,
+                server_time_us: self.time_us(),
+            }],
+            ClientPacket::LockstepInput {
+                frame,
+                inputs,
+                next_frame,
+                checksum,
+            } => {
+                let Some(lockstep) = &mut self.lockstep else {
+                    return Vec::new();
+                };
+                lockstep.add_inputs(player_idx, frame, &inputs);
+
+                let progress = &mut connection.lockstep;
+                progress.next_frame = progress.next_frame.max(Some(next_frame));
+                if let Some((frame, checksum)) = checksum
+                    && frame > progress.checked
+                    && let Some(same) = lockstep.verify(frame, checksum)
+                {
+                    progress.checked = frame;
+                    if !same {
+                        progress.desyncs += 1;
+                    }
+                }
+                Vec::new()
+            }
+            ClientPacket::PeerInput { frame, inputs } => {
+                if let Some(rollback) = &mut self.rollback
+                    && rollback.add_inputs(player_idx, frame, &inputs)
+                {
+                    self.relay(player_idx, frame, &inputs);
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Queue the inputs of a player for every other client.
+    fn relay(&mut self, player_idx: usize, frame: u64, inputs: &[(i8, i8)]) {
+        for (i, connection) in self.clients.iter().enumerate() {
+            let Some(connection) = connection.as_ref().filter(|_| i != player_idx) else {
+                continue;
+            };
+            let packet = ServerPacket::PeerInput {
+                player_idx,
+                frame,
+                inputs: inputs.to_vec(),
+            };
+            self.outbox.push((connection.addr, packet));
+        }
+    }
+
+    /// Queue the frames confirmed in `Mode::Lockstep` for every client which does not have them yet.
+    ///
+    /// Clients which have not started, or are too far behind, get the state to start from instead.
+    /// The frames are sent again until the client reports that it has come further.
+    fn send_confirmed(&mut self) {
+        let Some(lockstep) = &self.lockstep else {
+            return;
+        };
+        for connection in self.clients.iter().flatten() {
+            let confirmed = match connection.lockstep.next_frame {
+                Some(next_frame) if next_frame >= lockstep.frame() => continue,
+                Some(next_frame) => lockstep.confirmed(next_frame, lockstep::MAX_FRAMES_PER_PACKET),
+                None => None,
+            };
+            let packet = match confirmed {
+                Some(inputs) => ServerPacket::LockstepInputs {
+                    frame: connection.lockstep.next_frame.unwrap_or_default(),
+                    inputs,
+                    checksum: lockstep.newest_checksum(),
+                },
+                None => ServerPacket::LockstepStart {
+                    frame: lockstep.frame(),
+                    players: lockstep.game().players.clone(),
+                },
+            };
+            self.outbox.push((connection.addr, packet));
+        }
+    }
+
+    fn mode(&self) -> Mode {
+        if self.rollback.is_some() {
+            Mode::Rollback
+        } else if self.lockstep.is_some() {
+            Mode::Lockstep
+        } else {
+            Mode::Authoritative
+        }
+    }
+
+    /// Assign a player slot to a connecting client.
+    ///
+    /// A client which is already connected keeps its slot, since it may have missed the `ServerPacket::Accept`.
+    fn accept(
+        &mut self,
+        origin: SocketAddr,
+        existing: Option<usize>,
+        protocol_version: u32,
+    ) -> Result<usize, RejectReason> {
+        if protocol_version != PROTOCOL_VERSION {
+            return Err(RejectReason::VersionMismatch {
+                server_version: PROTOCOL_VERSION,
+            });
+        }
+
+        if let Some(player_idx) = existing {
+            return Ok(player_idx);
+        }
+
+        let connected = self.clients.iter().flatten().count();
+        if connected >= self.max_players {
+            return Err(RejectReason::ServerFull {
+                max_players: self.max_players,
+            });
+        }
+
+        Ok(self.connect(origin))
+    }
+
+    fn welcome(&self) -> ServerPacket {
+        ServerPacket::Welcome {
+            platforms: self.shared.platforms.clone(),
+            level_hash: self.shared.level_hash(),
+            physics: self.shared.physics,
+        }
+    }
+
+    /// The server time, in microseconds since the server started.
+    fn time_us(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    /// A one-line summary of the server, used when running headless.
+    fn status(&self, tickrate: usize) -> String {
+        let mut status = format!(
+            "mode: {}, physics: {}, ticks per second: {}, players: {}/{}, snapshots sent: {}",
+            self.mode(),
+            self.shared.physics,
+            tickrate,
+            self.clients.iter().flatten().count(),
+            self.max_players,
+            self.sequence
+        );
+        if let Some(lockstep) = &self.lockstep {
+            let desyncs: usize = self
+                .clients
+                .iter()
+                .flatten()
+                .map(|c| c.lockstep.desyncs)
+                .sum();
+            status += &format!(
+                ", frame: {}, stalls: {}, desyncs: {}",
+                lockstep.frame(),
+                lockstep.stats().stalls,
+                desyncs
+            );
+        }
+        status
+    }
+
+    /// The address of a connected client.
+    fn addr(&self, player_idx: usize) -> Option<SocketAddr> {
+        let connection = self.clients.get(player_idx)?.as_ref()?;
+        Some(connection.addr)
+    }
+
+    /// The next connected client after `current`, or `None` after the last one.
+    fn next_client(&self, current: Option<usize>) -> Option<usize> {
+        let start = current.map_or(0, |i| i + 1);
+        (start..self.clients.len()).find(|&i| self.clients[i].is_some())
+    }
+
+    fn find(&self, addr: SocketAddr) -> Option<usize> {
+        self.clients
+            .iter()
+            .position(|c| c.as_ref().is_some_and(|c| c.addr == addr))
+    }
+
+    fn connect(&mut self, addr: SocketAddr) -> usize {
+        let player_idx = self.shared.add_player();
+        if let Some(rollback) = &mut self.rollback {
+            rollback.add_player(player_idx);
+        }
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.add_member(player_idx);
+        }
+        if player_idx >= self.clients.len() {
+            self.clients.resize_with(player_idx + 1, || None);
+        }
+
+        self.clients[player_idx] = Some(Connection {
+            addr,
+            last_ack: 0,
+            snapshot_ack: 0,
+            last_seen: Instant::now(),
+            inputs: InputBuffer::new(self.input_buffer),
+            lockstep: LockstepProgress::default(),
+        });
+        player_idx
+    }
+
+    fn disconnect(&mut self, player_idx: usize) {
+        if let Some(connection) = self.clients[player_idx].take() {
+            self.departed.push(connection.addr);
+        }
+        self.shared.remove_player(player_idx);
+        if let Some(rollback) = &mut self.rollback {
+            rollback.remove_player(player_idx);
+        }
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.remove_member(player_idx);
+        }
+    }
+
+    /// Remove every client which has not sent anything within the timeout.
+    fn drop_timed_out(&mut self) {
+        for player_idx in 0..self.clients.len() {
+            let timed_out = self.clients[player_idx]
+                .as_ref()
+                .is_some_and(|c| c.last_seen.elapsed() > self.timeout);
+            if timed_out {
+                self.disconnect(player_idx);
+            }
+        }
+    }
+
+    /// Advance the simulation by one fixed time step, consuming at most one buffered input per player.
+    ///
+    /// In `Mode::Rollback`, the inputs relayed to the clients are simulated instead.
+    /// In `Mode::Lockstep`, every frame with the inputs of all players is simulated,
+    /// so that the server stays as far as the inputs allow.
+    fn simulate(&mut self) {
+        self.tick += 1;
+        if let Some(rollback) = &mut self.rollback {
+            rollback.advance();
+            self.shared.players = rollback.game().players.clone();
+            return;
+        }
+        if let Some(lockstep) = &mut self.lockstep {
+            // players who joined are only in the simulation once their first frame is
+            if self.clients.iter().any(Option::is_some) && lockstep.advance() > 0 {
+                self.shared.players = lockstep.game().players.clone();
+            }
+            return;
+        }
+        for (player_idx, connection) in self.clients.iter_mut().enumerate() {
+            let Some(connection) = connection else {
+                continue;
+            };
+
+            if let Some(movement) = connection.inputs.pop() {
+                connection.last_ack = movement.id;
+                self.shared
+                    .player_physics(player_idx, movement.dir, client::DELTA_TIME);
+            }
+        }
+    }
+}
+
+/// Send a snapshot to every client, delta compressed against the last snapshot each client received.
+///
+/// In `Mode::Rollback`, the snapshot is of the newest frame which can no longer be rolled back,
+/// and nothing is sent before there is one. In `Mode::Lockstep`, it is of the newest confirmed frame.
+fn broadcast(
+    state: &mut State,
+    server: &mut networking::Server,
+    codec: &dyn networking::Codec,
+) -> io::Result<()> {
+    let (tick, players) = match (&state.rollback, &state.lockstep) {
+        (Some(rollback), _) => match rollback.finalized() {
+            Some((frame, players)) => (frame, players.to_vec()),
+            None => return Ok(()),
+        },
+        (None, Some(lockstep)) => (lockstep.frame(), lockstep.game().players.clone()),
+        (None, None) => (state.tick, state.shared.players.clone()),
+    };
+    state.sequence += 1;
+    let time_us = state.time_us();
+    let mode = state.mode();
+
+    for (player_idx, connection) in state.clients.iter().enumerate() {
+        let Some(connection) = connection else {
+            continue;
+        };
+        // in the other modes, clients do not predict their own player from acknowledged messages
+        let player_hash = players
+            .get(player_idx)
+            .and_then(Option::as_ref)
+            .filter(|_| mode == Mode::Authoritative && connection.last_ack != 0)
+            .map(Player::state_hash);
         let response = ServerResponse {
-            game: state.shared.clone(),
-            ack_id: state.last_ack[i],
-            player_idx: i,
+            ack_id: connection.last_ack,
+            player_hash,
+            level_hash: state.shared.level_hash(),
+            sequence: state.sequence,
+            tick,
+            time_us,
+            input: connection.inputs.status(),
+            snapshot: state.history.encode(connection.snapshot_ack, &players),
         };
-        let serialized_state = serde_json::to_vec(&response).unwrap();
-        if let Err(e) = server.send(&serialized_state, addr) {
+        let serialized_state = codec.encode_server(&ServerPacket::Snapshot(response));
+        if let Err(e) = server.send(&serialized_state, connection.addr) {
             println!("{e}");
         };
     }
 
+    state.history.push(state.sequence, players);
+
     Ok(())
 }
 
-fn handle_server_inputs(events: &mut EventPump, running: &mut bool, tickrate: &mut usize) {
+/// Handle key presses in the server window.
+///
+/// Besides changing the tick rate, the simulated network conditions of the selected player can be adjusted
+/// with the same keys as on the client, using the arrow keys instead of `+`/`-` for ping.
+fn handle_server_inputs(
+    events: &mut EventPump,
+    running: &mut bool,
+    tickrate: &mut usize,
+    selected: &mut Option<usize>,
+    state: &State,
+    server: &mut networking::Server,
+) {
     for event in events.poll_iter() {
         use sdl2::{event::Event as Ev, keyboard::Keycode as Kc};
 
@@ -103,12 +595,305 @@ fn handle_server_inputs(events: &mut EventPump, running: &mut bool, tickrate: &m
             Ev::Quit { .. } => *running = false,
             Ev::KeyDown {
                 keycode: Some(kc), ..
-            } => match kc {
-                Kc::Plus => *tickrate += 1,
-                Kc::Minus => *tickrate = (*tickrate - 1).max(1),
-                _ => (),
-            },
+            } => {
+                let addr = selected.and_then(|i| state.addr(i));
+                let before = match addr {
+                    Some(addr) => server.conditions(addr),
+                    None => server.default_conditions(),
+                };
+                let mut conditions = before;
+
+                match kc {
+                    Kc::Plus => *tickrate += 1,
+                    Kc::Minus => *tickrate = (*tickrate - 1).max(1),
+                    Kc::Tab => *selected = state.next_client(*selected),
+                    Kc::Up => conditions.ping_ms += PING_INTERVAL,
+                    Kc::Down => conditions.ping_ms -= PING_INTERVAL.min(conditions.ping_ms),
+                    Kc::G => client::toggle_jitter(&mut conditions),
+                    _ => client::adjust_conditions(&mut conditions, kc),
+                }
+
+                if conditions != before {
+                    match addr {
+                        Some(addr) => server.set_conditions(addr, conditions),
+                        None => server.set_default_conditions(conditions),
+                    }
+                }
+            }
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        State {
+            clients: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            max_players: MAX_PLAYERS,
+            input_buffer: 1,
+            sequence: 0,
+            tick: 0,
+            started: Instant::now(),
+            history: snapshot::History::default(),
+            rollback: None,
+            lockstep: None,
+            outbox: Vec::new(),
+            departed: Vec::new(),
+            shared: Game::new(),
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        (std::net::Ipv4Addr::LOCALHOST, port).into()
+    }
+
+    fn connect() -> ClientPacket {
+        ClientPacket::Connect {
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    fn input(id: usize) -> ClientPacket {
+        ClientPacket::Input(Message {
+            id,
+            x: 1,
+            y: 0,
+            snapshot_ack: 0,
+            redundant: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn simulate_consumes_one_input_per_tick() {
+        let mut state = state();
+        state.handle_packet(addr(1234), connect());
+        for id in 1..=3 {
+            state.handle_packet(addr(1234), input(id));
+        }
+
+        state.simulate();
+        let connection = state.clients[0].as_ref().unwrap();
+        assert_eq!(connection.last_ack, 1);
+        assert_eq!(connection.inputs.status().depth, 2);
+
+        state.simulate();
+        state.simulate();
+        state.simulate();
+        let connection = state.clients[0].as_ref().unwrap();
+        assert_eq!(connection.last_ack, 3);
+        assert_eq!(state.tick, 4);
+        assert_eq!(connection.inputs.status().depth, 0);
+    }
+
+    #[test]
+    fn disconnect_keeps_player_ids() {
+        let mut state = state();
+        state.handle_packet(addr(1), connect());
+        state.handle_packet(addr(2), connect());
+        state.handle_packet(addr(1), ClientPacket::Disconnect);
+
+        assert!(state.shared.players[0].is_none());
+        assert!(state.shared.players[1].is_some());
+        assert_eq!(state.find(addr(2)), Some(1));
+        assert_eq!(state.departed, vec![addr(1)]);
+
+        state.handle_packet(addr(3), connect());
+        assert_eq!(state.find(addr(3)), Some(0));
+    }
+
+    #[test]
+    fn handshake_accepts_and_sends_level() {
+        let mut state = state();
+        let replies = state.handle_packet(addr(1), connect());
+        assert_eq!(
+            replies[0],
+            ServerPacket::Accept {
+                player_idx: 0,
+                max_players: MAX_PLAYERS,
+                mode: Mode::Authoritative,
+            }
+        );
+        assert!(matches!(replies[1], ServerPacket::Welcome { .. }));
+
+        // a repeated request keeps the same slot
+        let replies = state.handle_packet(addr(1), connect());
+        assert!(matches!(
+            replies[0],
+            ServerPacket::Accept { player_idx: 0, .. }
+        ));
+        assert_eq!(state.clients.iter().flatten().count(), 1);
+
+        let replies = state.handle_packet(addr(1), ClientPacket::LevelRequest);
+        assert!(matches!(replies[..], [ServerPacket::Welcome { .. }]));
+    }
+
+    #[test]
+    fn handshake_rejects_wrong_version_and_full_server() {
+        let mut state = state();
+        state.max_players = 1;
+
+        let replies = state.handle_packet(
+            addr(1),
+            ClientPacket::Connect {
+                protocol_version: PROTOCOL_VERSION + 1,
+            },
+        );
+        assert!(matches!(
+            replies[..],
+            [ServerPacket::Reject(RejectReason::VersionMismatch { .. })]
+        ));
+
+        state.handle_packet(addr(1), connect());
+        let replies = state.handle_packet(addr(2), connect());
+        assert!(matches!(
+            replies[..],
+            [ServerPacket::Reject(RejectReason::ServerFull {
+                max_players: 1
+            })]
+        ));
+    }
+
+    #[test]
+    fn unknown_clients_are_ignored() {
+        let mut state = state();
+        assert!(state.handle_packet(addr(1), input(1)).is_empty());
+        assert!(
+            state
+                .handle_packet(addr(1), ClientPacket::LevelRequest)
+                .is_empty()
+        );
+        assert!(state.clients.is_empty());
+        assert!(state.shared.players.is_empty());
+    }
+
+    #[test]
+    fn pings_are_answered_with_the_server_time() {
+        let mut state = state();
+        let ping = ClientPacket::Ping { client_time_us: 42 };
+        assert!(state.handle_packet(addr(1), ping).is_empty());
+
+        state.handle_packet(addr(1), connect());
+        std::thread::sleep(Duration::from_millis(1));
+        let ping = ClientPacket::Ping { client_time_us: 42 };
+        let replies = state.handle_packet(addr(1), ping);
+        assert!(matches!(
+            replies[..],
+            [ServerPacket::Pong {
+                client_time_us: 42,
+                server_time_us: 1_000..
+            }]
+        ));
+    }
+
+    #[test]
+    fn rollback_inputs_are_relayed_to_the_other_clients() {
+        let mut state = state();
+        state.rollback = Some(Rollback::new(Game::new(), 0));
+        for port in 1..=3 {
+            state.handle_packet(addr(port), connect());
+        }
+
+        let inputs = ClientPacket::PeerInput {
+            frame: 0,
+            inputs: vec![(1, 0)],
+        };
+        assert!(state.handle_packet(addr(2), inputs).is_empty());
+        let recipients: Vec<_> = state.outbox.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(recipients, vec![addr(1), addr(3)]);
+        assert!(matches!(
+            state.outbox[0].1,
+            ServerPacket::PeerInput { player_idx: 1, .. }
+        ));
+
+        // inputs which were already received are not relayed again
+        state.outbox.clear();
+        let inputs = ClientPacket::PeerInput {
+            frame: 0,
+            inputs: vec![(1, 0)],
+        };
+        state.handle_packet(addr(2), inputs);
+        assert!(state.outbox.is_empty());
+
+        state.simulate();
+        let player = state.shared.players[1].as_ref().unwrap();
+        assert!(player.velocity.x > 0.);
+    }
+
+    #[test]
+    fn lockstep_confirms_frames_with_every_input() {
+        let mut state = state();
+        state.lockstep = Some(Lockstep::new(Game::new(), 0));
+        state.handle_packet(addr(1), connect());
+        state.handle_packet(addr(2), connect());
+
+        state.send_confirmed();
+        assert!(matches!(
+            state.outbox[..],
+            [
+                (_, ServerPacket::LockstepStart { frame: 0, .. }),
+                (_, ServerPacket::LockstepStart { frame: 0, .. })
+            ]
+        ));
+        state.outbox.clear();
+
+        let input = |frame, next_frame| ClientPacket::LockstepInput {
+            frame,
+            inputs: vec![(1, 0); frame as usize + 1],
+            next_frame,
+            checksum: None,
+        };
+        state.handle_packet(addr(1), input(3, 0));
+        state.simulate();
+        assert!(state.shared.players.iter().all(Option::is_some));
+        assert_eq!(state.lockstep.as_ref().unwrap().frame(), 0);
+
+        state.handle_packet(addr(2), input(1, 0));
+        state.simulate();
+        assert_eq!(state.lockstep.as_ref().unwrap().frame(), 2);
+
+        // client 2 has simulated the first frame, and gets the second
+        state.handle_packet(addr(2), input(1, 1));
+        state.send_confirmed();
+        let frames: Vec<_> = state
+            .outbox
+            .iter()
+            .map(|(addr, packet)| match packet {
+                ServerPacket::LockstepInputs { frame, inputs, .. } => {
+                    (addr.port(), *frame, inputs.len())
+                }
+                _ => panic!("{packet:?}"),
+            })
+            .collect();
+        assert_eq!(frames, vec![(1, 0, 2), (2, 1, 1)]);
+    }
+
+    #[test]
+    fn select_next_client() {
+        let mut state = state();
+        for port in 1..=3 {
+            state.handle_packet(addr(port), connect());
+        }
+        state.handle_packet(addr(2), ClientPacket::Disconnect);
+
+        assert_eq!(state.next_client(None), Some(0));
+        assert_eq!(state.next_client(Some(0)), Some(2));
+        assert_eq!(state.next_client(Some(2)), None);
+        assert_eq!(state.addr(2), Some(addr(3)));
+    }
+
+    #[test]
+    fn silent_clients_time_out() {
+        let mut state = state();
+        state.timeout = Duration::ZERO;
+        state.handle_packet(addr(1), connect());
+        std::thread::sleep(Duration::from_millis(1));
+
+        state.drop_timed_out();
+        assert_eq!(state.find(addr(1)), None);
+        assert!(state.shared.players[0].is_none());
+    }
+}
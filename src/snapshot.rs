@@ -0,0 +1,213 @@
+//! Delta compression of player snapshots.
+//!
+//! Both the server and the client keep a `History` of recent snapshots.
+//! The server encodes each snapshot relative to the last one acknowledged by the client,
+//! and the client decodes it using its own copy of that baseline.
+
+use std::collections::VecDeque;
+
+use crate::model::*;
+
+/// The number of snapshots kept as possible baselines.
+///
+/// If the client acknowledges a snapshot older than this, the server sends a full snapshot instead.
+const HISTORY_LEN: usize = 32;
+
+/// A ring of the most recent snapshots, keyed by sequence number.
+#[derive(Default)]
+pub struct History {
+    snapshots: VecDeque<(usize, Vec<Option<Player>>)>,
+}
+
+impl History {
+    /// Store a snapshot, forgetting the oldest one if the history is full.
+    pub fn push(&mut self, sequence: usize, players: Vec<Option<Player>>) {
+        if self.snapshots.len() >= HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((sequence, players));
+    }
+
+    /// Look up a stored snapshot.
+    pub fn get(&self, sequence: usize) -> Option<&[Option<Player>]> {
+        self.snapshots
+            .iter()
+            .find(|(s, _)| *s == sequence)
+            .map(|(_, players)| &players[..])
+    }
+
+    /// Encode `players` relative to the snapshot `baseline`,
+    /// or in full if that snapshot is no longer stored.
+    pub fn encode(&self, baseline: usize, players: &[Option<Player>]) -> Snapshot {
+        match self.get(baseline) {
+            Some(old) => Snapshot::Delta {
+                baseline,
+                len: players.len(),
+                changes: diff(old, players),
+            },
+            None => Snapshot::Full(players.to_vec()),
+        }
+    }
+
+    /// Decode and store a received snapshot.
+    ///
+    /// Returns `None` if the snapshot is a delta against a baseline that is not stored.
+    pub fn decode(&mut self, sequence: usize, snapshot: Snapshot) -> Option<Vec<Option<Player>>> {
+        let players = match snapshot {
+            Snapshot::Full(players) => players,
+            Snapshot::Delta {
+                baseline,
+                len,
+                changes,
+            } => apply(self.get(baseline)?, len, changes),
+        };
+        self.push(sequence, players.clone());
+        Some(players)
+    }
+}
+
+/// Find the changed player slots between two snapshots.
+pub fn diff(old: &[Option<Player>], new: &[Option<Player>]) -> Vec<PlayerDelta> {
+    let mut changes = Vec::new();
+    for (player_idx, new) in new.iter().enumerate() {
+        let old = old.get(player_idx).and_then(Option::as_ref);
+        let change = match (old, new) {
+            (Some(_), None) => PlayerChange::Removed,
+            (None, None) => continue,
+            (Some(old), Some(new)) if old == new => continue,
+            (old, Some(new)) => PlayerChange::Changed {
+                pos: changed(old.map(|o| o.pos), new.pos),
+                velocity: changed(old.map(|o| o.velocity), new.velocity),
+                size: changed(old.map(|o| o.size), new.size),
+                state: changed(old.map(|o| o.state.clone()), new.state.clone()),
+            },
+        };
+        changes.push(PlayerDelta { player_idx, change });
+    }
+    changes
+}
+
+fn changed<T: PartialEq>(old: Option<T>, new: T) -> Option<T> {
+    if old.as_ref() == Some(&new) {
+        None
+    } else {
+        Some(new)
+    }
+}
+
+/// Apply changes to a baseline snapshot, producing a snapshot with `len` player slots.
+pub fn apply(old: &[Option<Player>], len: usize, changes: Vec<PlayerDelta>) -> Vec<Option<Player>> {
+    let mut players = old.to_vec();
+    players.resize(len, None);
+
+    for PlayerDelta { player_idx, change } in changes {
+        let Some(slot) = players.get_mut(player_idx) else {
+            continue;
+        };
+
+        match change {
+            PlayerChange::Removed => *slot = None,
+            PlayerChange::Changed {
+                pos,
+                velocity,
+                size,
+                state,
+            } => {
+                let player = slot.get_or_insert_with(Player::new);
+                if let Some(pos) = pos {
+                    player.pos = pos;
+                }
+                if let Some(velocity) = velocity {
+                    player.velocity = velocity;
+                }
+                if let Some(size) = size {
+                    player.size = size;
+                }
+                if let Some(state) = state {
+                    player.state = state;
+                }
+            }
+        }
+    }
+
+    players
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Vec2;
+
+    fn moved(x: f64) -> Option<Player> {
+        let mut player = Player::new();
+        player.pos.x = x;
+        Some(player)
+    }
+
+    #[test]
+    fn delta_only_contains_changed_fields() {
+        let old = vec![moved(1.), moved(2.)];
+        let new = vec![moved(1.), moved(3.)];
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 1);
+        let PlayerChange::Changed {
+            pos,
+            velocity,
+            size,
+            state,
+        } = &changes[0].change
+        else {
+            panic!("expected a change");
+        };
+        assert_eq!(changes[0].player_idx, 1);
+        assert_eq!(*pos, Some(Vec2::new(3., Player::new().pos.y)));
+        assert!(velocity.is_none() && size.is_none() && state.is_none());
+    }
+
+    #[test]
+    fn apply_reverses_diff() {
+        let old = vec![moved(1.), None, moved(2.)];
+        let new = vec![None, moved(5.), moved(2.), moved(7.)];
+
+        let changes = diff(&old, &new);
+        assert_eq!(apply(&old, new.len(), changes), new);
+    }
+
+    #[test]
+    fn encode_falls_back_to_full_snapshot() {
+        let mut history = History::default();
+        for sequence in 1..=HISTORY_LEN + 1 {
+            history.push(sequence, vec![moved(sequence as f64)]);
+        }
+
+        let players = vec![moved(0.)];
+        assert!(matches!(history.encode(1, &players), Snapshot::Full(_)));
+        assert!(matches!(
+            history.encode(2, &players),
+            Snapshot::Delta { baseline: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn decode_against_stored_baseline() {
+        let mut server = History::default();
+        let mut client = History::default();
+
+        let first = vec![moved(1.)];
+        server.push(1, first.clone());
+        let snapshot = server.encode(0, &first);
+        assert_eq!(client.decode(1, snapshot), Some(first.clone()));
+
+        let second = vec![moved(2.), moved(3.)];
+        let snapshot = server.encode(1, &second);
+        assert_eq!(client.decode(2, snapshot), Some(second));
+
+        let missing = Snapshot::Delta {
+            baseline: 40,
+            len: 0,
+            changes: Vec::new(),
+        };
+        assert_eq!(client.decode(3, missing), None);
+    }
+}
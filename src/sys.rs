@@ -5,7 +5,8 @@ use std::time::{Duration, Instant};
 const TITLE: &str = "netcode";
 const LOGICAL_WIDTH: u32 = 320;
 const LOGICAL_HEIGHT: u32 = 240;
-const SCALE: u32 = 4;
+/// Window scale, unless configured.
+pub const DEFAULT_SCALE: u32 = 4;
 const INT_SCALE: bool = true;
 const BLEND_MODE: sdl2::render::BlendMode = sdl2::render::BlendMode::None;
 
@@ -26,10 +27,11 @@ pub struct SdlContext {
 pub fn init_sdl_systems(
     sdl: &sdl2::Sdl,
     video: &sdl2::VideoSubsystem,
+    scale: u32,
 ) -> Result<SdlContext, Box<dyn std::error::Error>> {
     let events = sdl.event_pump()?;
     let window = video
-        .window(TITLE, LOGICAL_WIDTH * SCALE, LOGICAL_HEIGHT * SCALE)
+        .window(TITLE, LOGICAL_WIDTH * scale, LOGICAL_HEIGHT * scale)
         .build()?;
     let mut canvas = window.into_canvas().build()?;
     canvas.set_logical_size(LOGICAL_WIDTH, LOGICAL_HEIGHT)?;